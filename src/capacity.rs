@@ -0,0 +1,270 @@
+use crate::endpoint::{self, Listener, Peer, Stream};
+use crate::filter::Filter;
+use clap::{Args, ValueEnum};
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[derive(ValueEnum, Copy, Clone, Debug, Default, PartialEq)]
+pub enum WhenFull {
+    /// Leave new connections in the listen backlog until another connection closes
+    #[default]
+    Queue,
+    /// Close new connections immediately
+    Reject,
+}
+
+#[derive(Args, Debug)]
+pub struct CapacityOptions {
+    /// Most connections each listener may serve at once.
+    ///
+    /// UDP sessions count as connections, and datagrams which would start a session over the limit are dropped.
+    #[arg(long)]
+    pub max_connections: Option<NonZeroUsize>,
+
+    /// Most connections each listener may serve at once from a single IP address.
+    ///
+    /// Connections over this limit are always rejected, since their address isn't known until they're accepted.
+    /// Unix socket connections have no address, so they aren't counted.
+    #[arg(long)]
+    pub max_connections_per_ip: Option<NonZeroUsize>,
+
+    /// What to do with new connections once `--max-connections` is reached.
+    ///
+    /// UDP datagrams can't be queued, so they're always dropped.
+    #[arg(long, value_enum, default_value_t = WhenFull::Queue)]
+    pub when_full: WhenFull,
+}
+
+/// Connection limits for one listener.
+#[derive(Clone, Debug, Default)]
+pub struct Capacity {
+    total: Option<Arc<Semaphore>>,
+    per_ip: Option<Arc<PerIp>>,
+    when_full: WhenFull,
+}
+
+#[derive(Debug)]
+struct PerIp {
+    max: usize,
+    active: Mutex<HashMap<IpAddr, usize>>,
+}
+
+#[derive(Debug, Error)]
+pub enum Full {
+    #[error("too many connections")]
+    Total,
+    #[error("too many connections from this address")]
+    PerIp,
+}
+
+/// Held for as long as a connection is being served.
+pub struct Permit {
+    _total: Option<OwnedSemaphorePermit>,
+    per_ip: Option<(Arc<PerIp>, IpAddr)>,
+}
+
+impl Capacity {
+    /// Creates limits for a new listener, which aren't shared with any other listener.
+    pub fn new(options: &CapacityOptions) -> Self {
+        Self {
+            total: options
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max.get()))),
+            per_ip: options.max_connections_per_ip.map(|max| {
+                Arc::new(PerIp {
+                    max: max.get(),
+                    active: Default::default(),
+                })
+            }),
+            when_full: options.when_full,
+        }
+    }
+
+    /// Accepts the next connection which fits within the limits, closing any others.
+    pub async fn accept(
+        &self,
+        listener: &mut Listener,
+        filter: &Filter,
+    ) -> Result<(Stream, Permit), io::Error> {
+        loop {
+            let queued = match (&self.total, self.when_full) {
+                (Some(total), WhenFull::Queue) => Some(
+                    Arc::clone(total)
+                        .acquire_owned()
+                        .await
+                        .expect("semaphore is never closed"),
+                ),
+                (_, WhenFull::Queue | WhenFull::Reject) => None,
+            };
+
+            let stream = endpoint::accept(listener, filter).await?;
+            let peer = match stream.peer_addr() {
+                Ok(peer) => peer,
+                Err(e) => {
+                    log::debug!("Aborted connection dropped: {}", e);
+                    continue;
+                }
+            };
+
+            // unix sockets have no IP, so only count towards the total
+            let ip = match peer {
+                Peer::Inet(addr) => Some(addr.ip()),
+                Peer::Unix => None,
+            };
+            match self.admit(ip, queued) {
+                Ok(permit) => return Ok((stream, permit)),
+                Err(e) => log::info!("Rejected connection from {}: {}", peer, e),
+            }
+        }
+    }
+
+    /// Admits a connection from `ip` without waiting, e.g. a UDP session, if it fits within the limits.
+    pub fn try_admit(&self, ip: IpAddr) -> Result<Permit, Full> {
+        self.admit(Some(ip), None)
+    }
+
+    fn admit(
+        &self,
+        ip: Option<IpAddr>,
+        queued: Option<OwnedSemaphorePermit>,
+    ) -> Result<Permit, Full> {
+        let total = match (queued, &self.total) {
+            (Some(permit), _) => Some(permit),
+            (None, Some(total)) => match Arc::clone(total).try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => return Err(Full::Total),
+            },
+            (None, None) => None,
+        };
+
+        let per_ip = match (&self.per_ip, ip) {
+            (Some(per_ip), Some(ip)) if !per_ip.try_add(ip) => return Err(Full::PerIp),
+            (Some(per_ip), Some(ip)) => Some((Arc::clone(per_ip), ip)),
+            (Some(_), None) | (None, _) => None,
+        };
+
+        Ok(Permit {
+            _total: total,
+            per_ip,
+        })
+    }
+}
+
+impl PerIp {
+    fn try_add(&self, ip: IpAddr) -> bool {
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(ip.to_canonical()).or_insert(0);
+        if *count >= self.max {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    fn remove(&self, ip: IpAddr) {
+        let mut active = self.active.lock().unwrap();
+        let ip = ip.to_canonical();
+        if let Some(count) = active.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&ip);
+            }
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some((per_ip, ip)) = &self.per_ip {
+            per_ip.remove(*ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn per_ip_counts_each_address() {
+        let per_ip = PerIp {
+            max: 2,
+            active: Default::default(),
+        };
+        assert!(per_ip.try_add(ip("10.0.0.1")));
+        assert!(per_ip.try_add(ip("::ffff:10.0.0.1")));
+        assert!(!per_ip.try_add(ip("10.0.0.1")));
+        assert!(per_ip.try_add(ip("10.0.0.2")));
+        per_ip.remove(ip("10.0.0.1"));
+        assert!(per_ip.try_add(ip("10.0.0.1")));
+        per_ip.remove(ip("10.0.0.2"));
+        assert!(!per_ip.active.lock().unwrap().contains_key(&ip("10.0.0.2")));
+    }
+
+    #[test]
+    fn try_admit_applies_both_limits() {
+        let capacity = Capacity::new(&CapacityOptions {
+            max_connections: NonZeroUsize::new(2),
+            max_connections_per_ip: NonZeroUsize::new(1),
+            when_full: WhenFull::Queue,
+        });
+        let first = capacity.try_admit(ip("10.0.0.1")).unwrap();
+        assert!(matches!(
+            capacity.try_admit(ip("10.0.0.1")),
+            Err(Full::PerIp)
+        ));
+        let _second = capacity.try_admit(ip("10.0.0.2")).unwrap();
+        assert!(matches!(
+            capacity.try_admit(ip("10.0.0.3")),
+            Err(Full::Total)
+        ));
+        drop(first);
+        assert!(capacity.try_admit(ip("10.0.0.1")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_when_full() {
+        use std::time::Duration;
+        use tokio::io::AsyncReadExt;
+        use tokio::net::{TcpListener, TcpStream};
+        use tokio::time::timeout;
+
+        let capacity = Capacity::new(&CapacityOptions {
+            max_connections: NonZeroUsize::new(1),
+            max_connections_per_ip: None,
+            when_full: WhenFull::Reject,
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut listener = Listener::Tcp(listener);
+        let filter = Filter::default();
+
+        let _first = TcpStream::connect(addr).await.unwrap();
+        let (_accepted, permit) = capacity.accept(&mut listener, &filter).await.unwrap();
+
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let full = timeout(
+            Duration::from_millis(100),
+            capacity.accept(&mut listener, &filter),
+        );
+        assert!(full.await.is_err());
+        assert_eq!(second.read(&mut [0; 1]).await.unwrap(), 0);
+
+        drop(permit);
+        let third = TcpStream::connect(addr).await.unwrap();
+        let (accepted, _permit) = capacity.accept(&mut listener, &filter).await.unwrap();
+        assert_eq!(
+            accepted.peer_addr().unwrap(),
+            Peer::Inet(third.local_addr().unwrap())
+        );
+    }
+}
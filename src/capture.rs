@@ -0,0 +1,724 @@
+use crate::config::CAPTURE_QUEUE;
+use crate::endpoint::Peer;
+use clap::{Args, ValueEnum};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    /// Packets with synthesized IP and TCP headers, for Wireshark or tcpdump
+    Pcapng,
+    /// Timestamped hex and ASCII, for reading directly
+    Hexdump,
+}
+
+#[derive(Args, Debug)]
+pub struct CaptureOptions {
+    /// Directory to write a copy of each relayed TCP connection's data to, one connection per file
+    #[arg(long)]
+    pub capture: Option<PathBuf>,
+
+    /// Format of capture files (pcapng needs addresses, so connections from or to unix sockets aren't captured in it)
+    #[arg(long, value_enum, default_value_t = Format::Pcapng, requires = "capture")]
+    pub capture_format: Format,
+
+    /// Size at which each capture file is closed and the connection continues in a new one (e.g. `512K` or `10M`)
+    #[arg(long, default_value = "10M", value_parser = parse_size, requires = "capture")]
+    pub capture_max_size: u64,
+
+    /// Most capture files to keep for each connection, deleting the oldest once there are more
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..), requires = "capture")]
+    pub capture_max_files: u64,
+}
+
+/// Parses a number of bytes, optionally with a binary suffix (e.g. `512K`, `10M`, or `1G`).
+pub fn parse_size(s: &str) -> Result<u64, &'static str> {
+    let (number, scale) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    match number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+    {
+        Some(0) => Err("size must be greater than zero"),
+        Some(size) => Ok(size),
+        None => {
+            Err("size must be a whole number of bytes, optionally with K, M, or G (e.g. `10M`)")
+        }
+    }
+}
+
+/// Where and how to capture relayed connections.
+#[derive(Clone, Debug)]
+pub struct Capture(Arc<Settings>);
+
+#[derive(Debug)]
+struct Settings {
+    dir: PathBuf,
+    format: Format,
+    max_size: u64,
+    max_files: u64,
+}
+
+impl Capture {
+    /// Creates the capture directory, if capturing is enabled.
+    pub fn new(options: CaptureOptions) -> Result<Option<Self>, io::Error> {
+        let CaptureOptions {
+            capture,
+            capture_format,
+            capture_max_size,
+            capture_max_files,
+        } = options;
+        let Some(dir) = capture else {
+            return Ok(None);
+        };
+        fs::create_dir_all(&dir)?;
+        log::info!("Capturing relayed connections to: {}", dir.display());
+        Ok(Some(Self(Arc::new(Settings {
+            dir,
+            format: capture_format,
+            max_size: capture_max_size,
+            max_files: capture_max_files,
+        }))))
+    }
+
+    /// Starts capturing a connection, which is written in the background until the tap is dropped.
+    ///
+    /// Returns `None` if the format can't describe the connection, i.e. pcapng with a unix socket at either end.
+    pub fn start(&self, client: Peer, server: Peer) -> Option<Tap> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        let encoder = match (self.0.format, client, server) {
+            (Format::Pcapng, Peer::Inet(client), Peer::Inet(server)) => {
+                Encoder::Pcapng(Pcapng::new(client, server))
+            }
+            (Format::Pcapng, ..) => {
+                log::debug!(
+                    "Not capturing {} > {}, since pcapng needs addresses",
+                    client,
+                    server
+                );
+                return None;
+            }
+            (Format::Hexdump, ..) => Encoder::Hexdump(Hexdump { client, server }),
+        };
+
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let name = format!("{}-{}", millis, NEXT_ID.fetch_add(1, Relaxed));
+        let (tap, records) = queue(name.clone(), CAPTURE_QUEUE);
+        let settings = Arc::clone(&self.0);
+        tokio::spawn(async move {
+            if let Err(e) = write_all(&settings, &name, encoder, records).await {
+                log::warn!("Failed to capture {}: {}", name, e);
+            }
+        });
+        Some(tap)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    ToServer,
+    ToClient,
+}
+
+impl Direction {
+    /// Index of per-direction state, with the client's first.
+    fn index(self) -> usize {
+        match self {
+            Direction::ToServer => 0,
+            Direction::ToClient => 1,
+        }
+    }
+}
+
+/// Sends a connection's data to be captured.
+pub struct Tap {
+    name: String,
+    records: mpsc::UnboundedSender<Record>,
+    /// Bytes sent but not yet received, shared with `Records`
+    queued: Arc<AtomicUsize>,
+    /// Most bytes which may be queued at once
+    limit: usize,
+    /// Bytes dropped in each direction since the last record which was sent
+    dropped: [AtomicU64; 2],
+}
+
+/// Receives a connection's data to be captured.
+struct Records {
+    records: mpsc::UnboundedReceiver<Record>,
+    queued: Arc<AtomicUsize>,
+}
+
+/// Creates a queue which holds at most `limit` bytes of data.
+fn queue(name: String, limit: usize) -> (Tap, Records) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let queued = Arc::new(AtomicUsize::new(0));
+    let tap = Tap {
+        name,
+        records: tx,
+        queued: Arc::clone(&queued),
+        limit,
+        dropped: Default::default(),
+    };
+    let records = Records {
+        records: rx,
+        queued,
+    };
+    (tap, records)
+}
+
+impl Records {
+    async fn recv(&mut self) -> Option<Record> {
+        let record = self.records.recv().await?;
+        self.queued.fetch_sub(record.data.len(), Relaxed);
+        Some(record)
+    }
+}
+
+struct Record {
+    direction: Direction,
+    at: SystemTime,
+    data: Vec<u8>,
+    /// Bytes in the same direction which were dropped just before this
+    skipped: u64,
+}
+
+impl Tap {
+    /// Sends data to be captured, or drops it if the capture is falling behind.
+    pub fn record(&self, direction: Direction, data: &[u8]) {
+        let dropped = &self.dropped[direction.index()];
+        let skipped = dropped.load(Relaxed);
+        if self.queued.fetch_add(data.len(), Relaxed) + data.len() > self.limit {
+            self.queued.fetch_sub(data.len(), Relaxed);
+            if skipped == 0 {
+                log::warn!("Capture {} is falling behind, dropping data", self.name);
+            }
+            dropped.fetch_add(data.len() as u64, Relaxed);
+            return;
+        }
+        let record = Record {
+            direction,
+            at: SystemTime::now(),
+            data: data.to_vec(),
+            skipped,
+        };
+        // if writing failed, the error has already been logged
+        if self.records.send(record).is_ok() {
+            dropped.store(0, Relaxed);
+        }
+    }
+}
+
+async fn write_all(
+    settings: &Settings,
+    name: &str,
+    mut encoder: Encoder,
+    mut records: Records,
+) -> Result<(), io::Error> {
+    let mut files = Rotation {
+        settings,
+        name,
+        index: 0,
+        file: None,
+        size: 0,
+    };
+    files
+        .write(&encoder.start(SystemTime::now()), &encoder)
+        .await?;
+    while let Some(record) = records.recv().await {
+        files.write(&encoder.data(&record), &encoder).await?;
+    }
+    files
+        .write(&encoder.end(SystemTime::now()), &encoder)
+        .await?;
+    files.close().await
+}
+
+/// Splits a connection's capture across files of limited size, keeping only the newest.
+struct Rotation<'a> {
+    settings: &'a Settings,
+    name: &'a str,
+    index: u64,
+    file: Option<BufWriter<tokio::fs::File>>,
+    size: u64,
+}
+
+impl Rotation<'_> {
+    async fn write(&mut self, record: &[u8], encoder: &Encoder) -> Result<(), io::Error> {
+        if self.file.is_some() && self.size + record.len() as u64 > self.settings.max_size {
+            self.close().await?;
+            self.index += 1;
+            if let Some(old) = self.index.checked_sub(self.settings.max_files) {
+                let old = self.path(old);
+                // keep capturing, even if an old file can't be removed
+                if let Err(e) = tokio::fs::remove_file(&old).await {
+                    log::warn!("Failed to remove old capture {}: {}", old.display(), e);
+                }
+            }
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let file = tokio::fs::File::create(self.path(self.index)).await?;
+                let file = self.file.insert(BufWriter::new(file));
+                let preamble = encoder.preamble();
+                file.write_all(&preamble).await?;
+                self.size = preamble.len() as u64;
+                file
+            }
+        };
+        file.write_all(record).await?;
+        self.size += record.len() as u64;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<(), io::Error> {
+        if let Some(mut file) = self.file.take() {
+            file.flush().await?;
+        }
+        Ok(())
+    }
+
+    fn path(&self, index: u64) -> PathBuf {
+        let extension = match self.settings.format {
+            Format::Pcapng => "pcapng",
+            Format::Hexdump => "txt",
+        };
+        self.settings
+            .dir
+            .join(format!("{}-{}.{}", self.name, index, extension))
+    }
+}
+
+enum Encoder {
+    Pcapng(Pcapng),
+    Hexdump(Hexdump),
+}
+
+impl Encoder {
+    /// Written at the start of each file.
+    fn preamble(&self) -> Vec<u8> {
+        match self {
+            Encoder::Pcapng(_) => pcapng_preamble(),
+            Encoder::Hexdump(_) => Vec::new(),
+        }
+    }
+
+    fn start(&mut self, at: SystemTime) -> Vec<u8> {
+        match self {
+            Encoder::Pcapng(pcapng) => pcapng.handshake(at),
+            Encoder::Hexdump(hexdump) => hexdump.note(at, "opened"),
+        }
+    }
+
+    fn data(&mut self, record: &Record) -> Vec<u8> {
+        let Record {
+            direction,
+            at,
+            ref data,
+            skipped,
+        } = *record;
+        match self {
+            Encoder::Pcapng(pcapng) => {
+                // leave a gap in the sequence numbers, which shows up as missing segments
+                pcapng.skip(direction, skipped);
+                pcapng.data(direction, at, data)
+            }
+            Encoder::Hexdump(hexdump) => match skipped {
+                0 => hexdump.data(direction, at, data),
+                _ => [
+                    hexdump.skipped(direction, at, skipped),
+                    hexdump.data(direction, at, data),
+                ]
+                .concat(),
+            },
+        }
+    }
+
+    fn end(&mut self, at: SystemTime) -> Vec<u8> {
+        match self {
+            Encoder::Pcapng(pcapng) => pcapng.close(at),
+            Encoder::Hexdump(hexdump) => hexdump.note(at, "closed"),
+        }
+    }
+}
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// Largest payload in each synthesized packet, so lengths fit in the IP header.
+const MAX_SEGMENT: usize = 32 * 1024;
+
+/// Synthesizes the TCP packets that a direct connection between client and server would've sent.
+struct Pcapng {
+    client: SocketAddr,
+    server: SocketAddr,
+    /// Next sequence number sent by the client, then the server
+    seq: [u32; 2],
+}
+
+impl Pcapng {
+    fn new(client: SocketAddr, server: SocketAddr) -> Self {
+        Self {
+            client,
+            server,
+            seq: [0, 0],
+        }
+    }
+
+    fn handshake(&mut self, at: SystemTime) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.packet(&mut out, Direction::ToServer, TCP_SYN, at, &[]);
+        self.packet(&mut out, Direction::ToClient, TCP_SYN | TCP_ACK, at, &[]);
+        self.packet(&mut out, Direction::ToServer, TCP_ACK, at, &[]);
+        out
+    }
+
+    fn data(&mut self, direction: Direction, at: SystemTime, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for segment in data.chunks(MAX_SEGMENT) {
+            self.packet(&mut out, direction, TCP_PSH | TCP_ACK, at, segment);
+        }
+        out
+    }
+
+    fn skip(&mut self, direction: Direction, len: u64) {
+        let ours = direction.index();
+        self.seq[ours] = self.seq[ours].wrapping_add(len as u32);
+    }
+
+    fn close(&mut self, at: SystemTime) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.packet(&mut out, Direction::ToServer, TCP_FIN | TCP_ACK, at, &[]);
+        self.packet(&mut out, Direction::ToClient, TCP_FIN | TCP_ACK, at, &[]);
+        self.packet(&mut out, Direction::ToServer, TCP_ACK, at, &[]);
+        out
+    }
+
+    fn packet(
+        &mut self,
+        out: &mut Vec<u8>,
+        direction: Direction,
+        flags: u8,
+        at: SystemTime,
+        payload: &[u8],
+    ) {
+        let (from, to, ours, theirs) = match direction {
+            Direction::ToServer => (self.client, self.server, 0, 1),
+            Direction::ToClient => (self.server, self.client, 1, 0),
+        };
+        let seq = self.seq[ours];
+        let ack = if flags & TCP_ACK != 0 {
+            self.seq[theirs]
+        } else {
+            0
+        };
+        // SYN and FIN each take up a sequence number
+        let len = payload.len() + usize::from(flags & (TCP_SYN | TCP_FIN) != 0);
+        self.seq[ours] = seq.wrapping_add(len as u32);
+
+        let packet = ip_packet(from, to, seq, ack, flags, payload);
+        enhanced_packet_block(out, at, &packet);
+    }
+}
+
+fn pcapng_preamble() -> Vec<u8> {
+    let mut out = Vec::new();
+    // section header block
+    block(&mut out, 0x0A0D0D0A, |body| {
+        body.extend_from_slice(&0x1A2B3C4D_u32.to_le_bytes());
+        body.extend_from_slice(&1_u16.to_le_bytes());
+        body.extend_from_slice(&0_u16.to_le_bytes());
+        // section length unknown
+        body.extend_from_slice(&(-1_i64).to_le_bytes());
+    });
+    // interface description block, with raw IP packets and the default microsecond timestamps
+    block(&mut out, 0x00000001, |body| {
+        body.extend_from_slice(&101_u16.to_le_bytes());
+        body.extend_from_slice(&0_u16.to_le_bytes());
+        body.extend_from_slice(&0_u32.to_le_bytes());
+    });
+    out
+}
+
+fn enhanced_packet_block(out: &mut Vec<u8>, at: SystemTime, packet: &[u8]) {
+    let micros = at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    block(out, 0x00000006, |body| {
+        body.extend_from_slice(&0_u32.to_le_bytes());
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(packet);
+        body.resize(body.len().next_multiple_of(4), 0);
+    });
+}
+
+fn block(out: &mut Vec<u8>, kind: u32, body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&0_u32.to_le_bytes());
+    body(out);
+    let len = (out.len() - start + 4) as u32;
+    out.extend_from_slice(&len.to_le_bytes());
+    out[start + 4..start + 8].copy_from_slice(&len.to_le_bytes());
+}
+
+fn ip_packet(
+    from: SocketAddr,
+    to: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut tcp = Vec::with_capacity(20 + payload.len());
+    tcp.extend_from_slice(&from.port().to_be_bytes());
+    tcp.extend_from_slice(&to.port().to_be_bytes());
+    tcp.extend_from_slice(&seq.to_be_bytes());
+    tcp.extend_from_slice(&ack.to_be_bytes());
+    tcp.push(5 << 4);
+    tcp.push(flags);
+    tcp.extend_from_slice(&u16::MAX.to_be_bytes());
+    tcp.extend_from_slice(&[0, 0, 0, 0]);
+    tcp.extend_from_slice(payload);
+
+    let tcp_len = tcp.len() as u16;
+    let mut packet = Vec::with_capacity(40 + tcp.len());
+    let pseudo_header = match (from.ip().to_canonical(), to.ip().to_canonical()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&(20 + tcp_len).to_be_bytes());
+            // no fragment id, don't fragment, TTL 64, TCP, checksum filled in below
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            let checksum = checksum(&packet);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            let mut pseudo = Vec::with_capacity(12);
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&[0, 6]);
+            pseudo.extend_from_slice(&tcp_len.to_be_bytes());
+            pseudo
+        }
+        (src, dst) => {
+            let src = to_v6(src);
+            let dst = to_v6(dst);
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&tcp_len.to_be_bytes());
+            // TCP, hop limit 64
+            packet.extend_from_slice(&[6, 64]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+
+            let mut pseudo = Vec::with_capacity(40);
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&u32::from(tcp_len).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, 6]);
+            pseudo
+        }
+    };
+    let checksum = checksum(&[pseudo_header, tcp.clone()].concat());
+    tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&tcp);
+    packet
+}
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Internet checksum (RFC 1071).
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|pair| match *pair {
+            [hi, lo] => u32::from(u16::from_be_bytes([hi, lo])),
+            [hi] => u32::from(u16::from_be_bytes([hi, 0])),
+            _ => unreachable!(),
+        })
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+struct Hexdump {
+    client: Peer,
+    server: Peer,
+}
+
+impl Hexdump {
+    fn note(&self, at: SystemTime, what: &str) -> Vec<u8> {
+        format!(
+            "{} {} > {} {}\n\n",
+            timestamp(at),
+            self.client,
+            self.server,
+            what
+        )
+        .into_bytes()
+    }
+
+    fn skipped(&self, direction: Direction, at: SystemTime, len: u64) -> Vec<u8> {
+        let (from, to) = self.endpoints(direction);
+        format!(
+            "{} {} > {} {} bytes not captured\n\n",
+            timestamp(at),
+            from,
+            to,
+            len
+        )
+        .into_bytes()
+    }
+
+    fn data(&self, direction: Direction, at: SystemTime, data: &[u8]) -> Vec<u8> {
+        let (from, to) = self.endpoints(direction);
+        let mut out = format!("{} {} > {} {} bytes\n", timestamp(at), from, to, data.len());
+        for (i, line) in data.chunks(16).enumerate() {
+            let _ = write!(out, "{:08x} ", i * 16);
+            for j in 0..16 {
+                if j == 8 {
+                    out.push(' ');
+                }
+                match line.get(j) {
+                    Some(byte) => {
+                        let _ = write!(out, " {:02x}", byte);
+                    }
+                    None => out.push_str("   "),
+                }
+            }
+            out.push_str("  |");
+            out.extend(line.iter().map(|&byte| match byte {
+                0x20..=0x7e => char::from(byte),
+                _ => '.',
+            }));
+            out.push_str("|\n");
+        }
+        out.push('\n');
+        out.into_bytes()
+    }
+
+    fn endpoints(&self, direction: Direction) -> (Peer, Peer) {
+        match direction {
+            Direction::ToServer => (self.client, self.server),
+            Direction::ToClient => (self.server, self.client),
+        }
+    }
+}
+
+/// Seconds since the Unix epoch, to the microsecond.
+fn timestamp(at: SystemTime) -> String {
+    let since = at.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+    format!("{}.{:06}", since.as_secs(), since.subsec_micros())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rustfmt::skip]
+    mod size {
+        use super::*;
+
+        case!(bytes: assert_eq!(parse_size("1000"), Ok(1000)));
+        case!(kibibytes: assert_eq!(parse_size("512K"), Ok(512 * 1024)));
+        case!(mebibytes: assert_eq!(parse_size("10m"), Ok(10 * 1024 * 1024)));
+        case!(zero: assert!(parse_size("0").is_err()));
+        case!(fractional: assert!(parse_size("1.5M").is_err()));
+        case!(overflow: assert!(parse_size("99999999999G").is_err()));
+    }
+
+    #[rustfmt::skip]
+    mod checksums {
+        use super::*;
+
+        // example IPv4 header from RFC 1071 errata / Wikipedia, with the checksum zeroed
+        case!(ipv4_header: assert_eq!(checksum(&[
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11,
+            0x00, 0x00, 0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ]), 0xb861));
+        case!(odd_length: assert_eq!(checksum(&[0xff]), 0x00ff));
+    }
+
+    #[test]
+    fn pcapng_tracks_sequence_numbers() {
+        let client = "10.0.0.1:5000".parse().unwrap();
+        let server = "10.0.0.2:80".parse().unwrap();
+        let mut pcapng = Pcapng::new(client, server);
+        pcapng.handshake(UNIX_EPOCH);
+        assert_eq!(pcapng.seq, [1, 1]);
+        pcapng.data(Direction::ToServer, UNIX_EPOCH, b"hello");
+        pcapng.data(Direction::ToClient, UNIX_EPOCH, &[0; MAX_SEGMENT + 1]);
+        assert_eq!(pcapng.seq, [6, 1 + MAX_SEGMENT as u32 + 1]);
+        pcapng.skip(Direction::ToServer, 10);
+        assert_eq!(pcapng.seq[0], 16);
+    }
+
+    #[tokio::test]
+    async fn tap_drops_when_full() {
+        let (tap, mut records) = queue("test".to_string(), 8);
+        tap.record(Direction::ToServer, b"first");
+        tap.record(Direction::ToServer, b"dropped");
+        assert_eq!(records.recv().await.unwrap().data, b"first");
+        tap.record(Direction::ToServer, b"third");
+        let third = records.recv().await.unwrap();
+        assert_eq!((third.data.as_slice(), third.skipped), (&b"third"[..], 7));
+    }
+
+    #[test]
+    fn hexdump_lines() {
+        let hexdump = Hexdump {
+            client: Peer::Inet("10.0.0.1:5000".parse().unwrap()),
+            server: Peer::Inet("10.0.0.2:80".parse().unwrap()),
+        };
+        let at = UNIX_EPOCH + Duration::from_micros(1_500_000);
+        let out = hexdump.data(Direction::ToClient, at, b"HTTP/1.1 200 OK\r\n");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "1.500000 10.0.0.2:80 > 10.0.0.1:5000 17 bytes\n\
+             00000000  48 54 54 50 2f 31 2e 31  20 32 30 30 20 4f 4b 0d  |HTTP/1.1 200 OK.|\n\
+             00000010  0a                                                |.|\n\n"
+        );
+    }
+
+    #[test]
+    fn pcapng_skips_unix_sockets() {
+        let capture = Capture(Arc::new(Settings {
+            dir: std::env::temp_dir(),
+            format: Format::Pcapng,
+            max_size: 1,
+            max_files: 1,
+        }));
+        let server = Peer::Inet("10.0.0.2:80".parse().unwrap());
+        assert!(capture.start(Peer::Unix, server).is_none());
+    }
+}
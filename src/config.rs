@@ -1 +1,9 @@
+use std::time::Duration;
+
 pub const COPY_BUFFER_SIZE: usize = 64 * 1024;
+/// Most bytes to buffer for each capture, beyond which data is dropped instead of slowing the relay.
+pub const CAPTURE_QUEUE: usize = 256 * 1024;
+
+pub const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const WEBSOCKET_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const PROXY_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
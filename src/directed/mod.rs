@@ -1,3 +1,4 @@
+use crate::capacity::Capacity;
 use crate::directed::redir::Rules;
 use crate::directed::routes::{State, respond_to_request};
 use crate::err::Error;
@@ -8,14 +9,27 @@ mod redir;
 mod routes;
 
 pub async fn main(options: opt::Options) -> Result<(), Error> {
-    let opt::Options { listen, from, to } = options;
+    let opt::Options {
+        listen,
+        from,
+        to,
+        filter,
+        capacity,
+    } = options;
 
     let state = State {
         client: http::make_client()?,
         rules: Rules::zip(from, to)?,
     };
 
-    http::run_simple_server(listen, state, respond_to_request).await?;
+    http::run_simple_server(
+        listen,
+        filter.into(),
+        Capacity::new(&capacity),
+        state,
+        respond_to_request,
+    )
+    .await?;
 
     Ok(())
 }
@@ -1,6 +1,8 @@
+use crate::capacity::CapacityOptions;
 use crate::directed::redir::{From, To};
+use crate::endpoint::ListenAddr;
+use crate::filter::FilterOptions;
 use clap::Args;
-use std::net::SocketAddr;
 
 /// Redirect HTTP traffic somewhere else
 #[derive(Args, Debug)]
@@ -12,9 +14,10 @@ pub struct Options {
 Examples:
     - 127.0.0.1:3000
     - 0.0.0.0:80
-    - [2001:db8::1]:8080"
+    - [2001:db8::1]:8080
+    - unix:/run/directed.sock"
     )]
-    pub listen: SocketAddr,
+    pub listen: ListenAddr,
 
     #[arg(
         help = "Path prefixes to redirect from (--help for more)",
@@ -42,4 +45,10 @@ Examples:
     )]
     #[arg(short, long, required = true, display_order = 0)]
     pub to: Vec<To>,
+
+    #[command(flatten)]
+    pub filter: FilterOptions,
+
+    #[command(flatten)]
+    pub capacity: CapacityOptions,
 }
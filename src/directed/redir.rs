@@ -151,6 +151,7 @@ pub enum Action {
 
 #[cfg(test)]
 #[rustfmt::skip]
+#[allow(clippy::redundant_pattern_matching)]
 mod tests {
     use super::*;
 
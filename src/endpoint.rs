@@ -0,0 +1,297 @@
+use crate::err::{AppliesTo, IoErrorExt};
+use crate::filter::Filter;
+use crate::opt::SocketAddrsFromDns;
+use crate::tcp;
+use std::fmt;
+use std::io;
+use std::net::{AddrParseError, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// Either end of a connection: a socket address, or a unix socket, which doesn't have one.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Peer {
+    Inet(SocketAddr),
+    Unix,
+}
+
+#[derive(Debug, Error)]
+pub enum BadEndpoint {
+    #[error("unix socket path is empty")]
+    EmptyPath,
+    #[error("{0}")]
+    Addr(#[from] AddrParseError),
+    #[error("{0}")]
+    Resolve(#[from] io::Error),
+}
+
+/// Where to listen for connections: a socket address, or a unix socket like `unix:/path/to.sock`.
+#[derive(Clone, Debug)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// Where to connect to: a hostname or socket address, or a unix socket like `unix:/path/to.sock`.
+#[derive(Clone, Debug)]
+pub enum ConnectAddr {
+    Tcp(SocketAddrsFromDns),
+    Unix(PathBuf),
+}
+
+fn unix_path(s: &str) -> Option<Result<PathBuf, BadEndpoint>> {
+    let path = s.strip_prefix(UNIX_PREFIX)?;
+    Some(match path {
+        "" => Err(BadEndpoint::EmptyPath),
+        path => Ok(PathBuf::from(path)),
+    })
+}
+
+impl FromStr for ListenAddr {
+    type Err = BadEndpoint;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match unix_path(s) {
+            Some(path) => Ok(ListenAddr::Unix(path?)),
+            None => Ok(ListenAddr::Tcp(s.parse()?)),
+        }
+    }
+}
+
+impl FromStr for ConnectAddr {
+    type Err = BadEndpoint;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match unix_path(s) {
+            Some(path) => Ok(ConnectAddr::Unix(path?)),
+            None => Ok(ConnectAddr::Tcp(s.parse()?)),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Inet(addr) => write!(f, "{}", addr),
+            Peer::Unix => f.write_str("unix socket"),
+        }
+    }
+}
+
+impl fmt::Display for ConnectAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectAddr::Tcp(addrs) => write!(f, "{}", addrs.orig()),
+            ConnectAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix sockets aren't supported on this platform",
+    )
+}
+
+pub async fn bind(addr: &ListenAddr) -> Result<Listener, io::Error> {
+    match addr {
+        ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+        #[cfg(unix)]
+        ListenAddr::Unix(path) => Ok(Listener::Unix(bind_unix(path)?)),
+        #[cfg(not(unix))]
+        ListenAddr::Unix(_) => Err(unsupported()),
+    }
+}
+
+/// Binds a unix socket, replacing the socket file if it was left behind by a previous process.
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> Result<UnixListener, io::Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            let is_socket = std::fs::symlink_metadata(path)?.file_type().is_socket();
+            // if nothing is listening, connecting is refused
+            let is_stale = is_socket
+                && std::os::unix::net::UnixStream::connect(path)
+                    .is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused);
+            if !is_stale {
+                return Err(e);
+            }
+            log::info!("Removing stale socket: {}", path.display());
+            std::fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        result => result,
+    }
+}
+
+/// Accepts the next connection which the filter permits, closing any others.
+///
+/// Unix socket peers have no address to filter by, so they're always permitted;
+/// the socket file's permissions decide who may connect instead.
+pub async fn accept(listener: &mut Listener, filter: &Filter) -> Result<Stream, io::Error> {
+    match listener {
+        Listener::Tcp(listener) => Ok(Stream::Tcp(tcp::accept(listener, filter).await?)),
+        #[cfg(unix)]
+        Listener::Unix(listener) => loop {
+            match listener.accept().await {
+                Ok((stream, _addr)) => return Ok(Stream::Unix(stream)),
+                Err(e) => match e.applies_to() {
+                    AppliesTo::Connection => log::debug!("Aborted connection dropped: {}", e),
+                    AppliesTo::Listener => return Err(e),
+                },
+            }
+        },
+    }
+}
+
+pub async fn connect(addr: &ConnectAddr) -> Result<Stream, io::Error> {
+    match addr {
+        ConnectAddr::Tcp(addrs) => Ok(Stream::Tcp(tcp::connect(addrs).await?)),
+        #[cfg(unix)]
+        ConnectAddr::Unix(path) => Ok(Stream::Unix(UnixStream::connect(path).await?)),
+        #[cfg(not(unix))]
+        ConnectAddr::Unix(_) => Err(unsupported()),
+    }
+}
+
+impl Stream {
+    /// The remote end of the connection.
+    pub fn peer_addr(&self) -> Result<Peer, io::Error> {
+        match self {
+            Stream::Tcp(stream) => Ok(Peer::Inet(stream.peer_addr()?)),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(Peer::Unix),
+        }
+    }
+
+    /// The local end of the connection.
+    pub fn local_addr(&self) -> Result<Peer, io::Error> {
+        match self {
+            Stream::Tcp(stream) => Ok(Peer::Inet(stream.local_addr()?)),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(Peer::Unix),
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rustfmt::skip]
+    mod parse {
+        use super::*;
+
+        fn listen(s: &str) -> String {
+            s.parse::<ListenAddr>().map_or_else(|e| format!("error: {}", e), |a| format!("{:?}", a))
+        }
+
+        case!(tcp: assert_eq!(listen("127.0.0.1:80"), "Tcp(127.0.0.1:80)"));
+        case!(unix: assert_eq!(listen("unix:/run/app.sock"), r#"Unix("/run/app.sock")"#));
+        case!(unix_relative: assert_eq!(listen("unix:app.sock"), r#"Unix("app.sock")"#));
+        case!(unix_empty: assert_eq!(listen("unix:"), "error: unix socket path is empty"));
+        case!(bad_addr: assert_eq!(listen("localhost"), "error: invalid socket address syntax"));
+        case!(display: assert_eq!("unix:/run/app.sock".parse::<ConnectAddr>().unwrap().to_string(), "unix:/run/app.sock"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_replaces_stale_socket() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sock");
+        let addr = ListenAddr::Unix(path.clone());
+
+        let stale = bind(&addr).await.unwrap();
+        assert!(bind(&addr).await.is_err());
+        drop(stale);
+
+        let mut listener = bind(&addr).await.unwrap();
+        let mut client = connect(&ConnectAddr::Unix(path.clone())).await.unwrap();
+        let mut server = accept(&mut listener, &Filter::default()).await.unwrap();
+        assert_eq!(server.peer_addr().unwrap(), Peer::Unix);
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
@@ -0,0 +1,152 @@
+use clap::Args;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Block of IP addresses (e.g. `10.0.0.0/8` or `2001:db8::/32`), or a single address.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+#[derive(Debug, Error)]
+pub enum BadCidr {
+    #[error("invalid IP address: {0}")]
+    InvalidAddr(String),
+    #[error("invalid prefix length: {0}")]
+    InvalidPrefix(String),
+}
+
+impl FromStr for Cidr {
+    type Err = BadCidr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| BadCidr::InvalidAddr(addr.to_string()))?;
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= max => prefix,
+                _ => return Err(BadCidr::InvalidPrefix(prefix.to_string())),
+            },
+            None => max,
+        };
+        // peers are matched by their canonical address, so IPv4-mapped blocks are matched as IPv4
+        match addr.to_canonical() {
+            IpAddr::V4(v4) if addr.is_ipv6() && prefix >= 96 => Ok(Cidr {
+                addr: IpAddr::V4(v4),
+                prefix: prefix - 96,
+            }),
+            _ => Ok(Cidr { addr, prefix }),
+        }
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            (IpAddr::V4(_), IpAddr::V6(_)) | (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+/// Which peers may connect to a listener.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    allow: Arc<[Cidr]>,
+    deny: Arc<[Cidr]>,
+}
+
+impl Filter {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        Self {
+            allow: allow.into(),
+            deny: deny.into(),
+        }
+    }
+
+    /// Denied addresses are always rejected, and if any addresses are allowed, all others are rejected.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip));
+        let denied = self.deny.iter().any(|cidr| cidr.contains(ip));
+        allowed && !denied
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct FilterOptions {
+    /// Only accept connections from these addresses (e.g. `10.0.0.0/8` or `2001:db8::1`).
+    ///
+    /// May be given multiple times. If not given, all addresses are allowed, unless denied.
+    #[arg(long)]
+    pub allow: Vec<Cidr>,
+
+    /// Reject connections from these addresses, even if allowed.
+    ///
+    /// May be given multiple times.
+    #[arg(long)]
+    pub deny: Vec<Cidr>,
+}
+
+impl From<FilterOptions> for Filter {
+    fn from(options: FilterOptions) -> Self {
+        let FilterOptions { allow, deny } = options;
+        Filter::new(allow, deny)
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    case!(v4_block: assert!(cidr("10.0.0.0/8").contains(ip("10.1.2.3"))));
+    case!(v4_outside: assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1"))));
+    case!(v4_host_bits_ignored: assert!(cidr("192.168.1.77/24").contains(ip("192.168.1.1"))));
+    case!(v4_single: assert!(cidr("127.0.0.1").contains(ip("127.0.0.1")) && !cidr("127.0.0.1").contains(ip("127.0.0.2"))));
+    case!(v4_everything: assert!(cidr("0.0.0.0/0").contains(ip("8.8.8.8"))));
+    case!(v6_block: assert!(cidr("2001:db8::/32").contains(ip("2001:db8:1::1"))));
+    case!(v6_outside: assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::1"))));
+    case!(v4_mapped: assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.0.0.1"))));
+    case!(v4_mapped_block: assert_eq!(cidr("::ffff:10.0.0.0/104"), cidr("10.0.0.0/8")));
+    case!(v4_mapped_single: assert_eq!(cidr("::ffff:10.0.0.1"), cidr("10.0.0.1")));
+    case!(families_differ: assert!(!cidr("::/0").contains(ip("10.0.0.1"))));
+
+    case!(bad_addr: assert!(matches!("10.0.0/8".parse::<Cidr>(), Err(BadCidr::InvalidAddr(_)))));
+    case!(bad_prefix: assert!(matches!("10.0.0.0/33".parse::<Cidr>(), Err(BadCidr::InvalidPrefix(_)))));
+
+    case!(empty_permits_all: assert!(Filter::default().permits(ip("1.2.3.4"))));
+    case!(allow_only: assert!(!Filter::new(vec![cidr("10.0.0.0/8")], vec![]).permits(ip("1.2.3.4"))));
+    case!(deny_wins: assert!(!Filter::new(vec![cidr("10.0.0.0/8")], vec![cidr("10.0.0.1")]).permits(ip("10.0.0.1"))));
+    case!(deny_only: assert!(Filter::new(vec![], vec![cidr("10.0.0.1")]).permits(ip("10.0.0.2"))));
+}
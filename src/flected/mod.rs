@@ -1,3 +1,4 @@
+use crate::capacity::Capacity;
 use crate::err::Error;
 use crate::flected::routes::respond_to_request;
 use crate::http;
@@ -8,9 +9,20 @@ pub mod opt;
 mod routes;
 
 pub async fn main(options: opt::Options) -> Result<(), Error> {
-    let opt::Options { listen } = options;
+    let opt::Options {
+        listen,
+        filter,
+        capacity,
+    } = options;
 
-    http::run_simple_server(listen, Default::default(), respond_to_request).await?;
+    http::run_simple_server(
+        listen,
+        filter.into(),
+        Capacity::new(&capacity),
+        Default::default(),
+        respond_to_request,
+    )
+    .await?;
 
     Ok(())
 }
@@ -1,9 +1,17 @@
+use crate::capacity::CapacityOptions;
+use crate::endpoint::ListenAddr;
+use crate::filter::FilterOptions;
 use clap::Args;
-use std::net::SocketAddr;
 
 /// Temporarily upload and serve files from memory
 #[derive(Args, Debug)]
 pub struct Options {
-    /// Socket address to listen on
-    pub listen: SocketAddr,
+    /// Socket address to listen on, or a unix socket (e.g. `unix:/run/app.sock`)
+    pub listen: ListenAddr,
+
+    #[command(flatten)]
+    pub filter: FilterOptions,
+
+    #[command(flatten)]
+    pub capacity: CapacityOptions,
 }
@@ -1,3 +1,7 @@
+use crate::capacity::Capacity;
+use crate::endpoint::{self, ListenAddr};
+use crate::filter::Filter;
+use crate::shutdown;
 use hyper::body::{Body, Incoming};
 use hyper::service::service_fn;
 use hyper::{Request, Response};
@@ -9,9 +13,8 @@ use hyper_util::server::conn::auto;
 use std::convert::Infallible;
 use std::future::Future;
 use std::io;
-use std::net::SocketAddr;
+use std::pin::pin;
 use std::sync::Arc;
-use tokio::net::TcpListener;
 
 pub type ProxyClient = Client<HttpsConnector<HttpConnector>, Incoming>;
 
@@ -27,7 +30,9 @@ pub fn make_client() -> Result<ProxyClient, io::Error> {
 }
 
 pub async fn run_simple_server<S, F, B>(
-    addr: SocketAddr,
+    addr: ListenAddr,
+    filter: Filter,
+    capacity: Capacity,
     state: S,
     handle_req: F,
 ) -> Result<(), io::Error>
@@ -39,23 +44,35 @@ where
     <B as Body>::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
 {
     let state = Arc::new(state);
-    let listener = TcpListener::bind(addr).await?;
+    let mut listener = endpoint::bind(&addr).await?;
 
     loop {
-        let (tcp, _) = listener.accept().await?;
-        let io = TokioIo::new(tcp);
+        let (stream, permit) = tokio::select! {
+            accepted = capacity.accept(&mut listener, &filter) => accepted?,
+            () = shutdown::requested() => return Ok(()),
+        };
+        let io = TokioIo::new(stream);
 
         let state = Arc::clone(&state);
         tokio::spawn(async move {
+            let _draining = shutdown::guard();
+            let _permit = permit;
             let serve = service_fn(move |req| {
                 let state = Arc::clone(&state);
                 async move { Ok::<_, Infallible>(handle_req(req, &state).await) }
             });
 
-            if let Err(e) = auto::Builder::new(TokioExecutor::new())
-                .serve_connection_with_upgrades(io, serve)
-                .await
-            {
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let mut conn = pin!(builder.serve_connection_with_upgrades(io, serve));
+            let done = tokio::select! {
+                done = conn.as_mut() => done,
+                () = shutdown::requested() => {
+                    // finish in-flight requests, but close idle keep-alive connections
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(e) = done {
                 log::error!("Error serving connection: {}", e);
             }
         });
@@ -0,0 +1,54 @@
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const NONCE_LEN: usize = 32;
+const TAG_LEN: usize = 32;
+
+/// Shared secret used to authenticate clients to the server.
+#[derive(Clone)]
+pub struct Secret(hmac::Key);
+
+impl Secret {
+    pub fn new(secret: &[u8]) -> Self {
+        Self(hmac::Key::new(hmac::HMAC_SHA256, secret))
+    }
+}
+
+/// Sends a random nonce, and verifies that the peer signed it with the shared secret.
+pub async fn challenge(
+    mut conn: impl AsyncRead + AsyncWrite + Unpin,
+    secret: &Secret,
+) -> Result<(), io::Error> {
+    let mut nonce = [0; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|ring::error::Unspecified| io::Error::other("failed to generate nonce"))?;
+    conn.write_all(&nonce).await?;
+    conn.flush().await?;
+
+    let mut tag = [0; TAG_LEN];
+    conn.read_exact(&mut tag).await?;
+    match hmac::verify(&secret.0, &nonce, &tag) {
+        Ok(()) => Ok(()),
+        Err(ring::error::Unspecified) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "authentication failed",
+        )),
+    }
+}
+
+/// Signs the peer's nonce with the shared secret.
+pub async fn respond(
+    mut conn: impl AsyncRead + AsyncWrite + Unpin,
+    secret: &Secret,
+) -> Result<(), io::Error> {
+    let mut nonce = [0; NONCE_LEN];
+    conn.read_exact(&mut nonce).await?;
+
+    let tag = hmac::sign(&secret.0, &nonce);
+    conn.write_all(tag.as_ref()).await?;
+    conn.flush().await?;
+    Ok(())
+}
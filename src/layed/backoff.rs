@@ -1,27 +1,79 @@
-use std::ops::RangeInclusive;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::time::Duration;
+
+/// Range of delays to back off between, doubling after each failure.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Schedule {
+    pub min: Duration,
+    pub max: Duration,
+    /// Fraction of each delay to randomly shorten it by, from 0 up to (not including) 1, so many peers don't retry in lockstep
+    pub jitter: f64,
+}
 
 pub struct Backoff {
-    value: u8,
-    min: u8,
-    max: u8,
+    value: Duration,
+    schedule: Schedule,
 }
 
 impl Backoff {
-    pub fn new(range: RangeInclusive<u8>) -> Self {
+    pub fn new(schedule: Schedule) -> Self {
         Backoff {
-            value: *range.start(),
-            min: *range.start(),
-            max: *range.end(),
+            value: schedule.min,
+            schedule,
         }
     }
 
-    pub fn next(&mut self) -> u8 {
+    pub fn next(&mut self) -> Duration {
         let old_value = self.value;
-        self.value = old_value.saturating_mul(2).min(self.max);
-        old_value
+        self.value = old_value.saturating_mul(2).min(self.schedule.max);
+        let delay = old_value.mul_f64(1.0 - self.schedule.jitter * random_fraction());
+        // whole milliseconds, so it's logged nicely, and never zero, so retries can't spin
+        Duration::from_millis((delay.as_millis() as u64).max(1))
     }
 
     pub fn reset(&mut self) {
-        self.value = self.min;
+        self.value = self.schedule.min;
+    }
+}
+
+/// Uniformly random in `[0, 1)`.
+fn random_fraction() -> f64 {
+    let mut bytes = [0; 4];
+    match SystemRandom::new().fill(&mut bytes) {
+        Ok(()) => f64::from(u32::from_be_bytes(bytes)) / (f64::from(u32::MAX) + 1.0),
+        Err(ring::error::Unspecified) => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEDULE: Schedule = Schedule {
+        min: Duration::from_millis(500),
+        max: Duration::from_secs(2),
+        jitter: 0.0,
+    };
+
+    #[test]
+    fn doubles_up_to_max() {
+        let mut backoff = Backoff::new(SCHEDULE);
+        let delays = [(); 5].map(|()| backoff.next().as_millis());
+        assert_eq!(delays, [500, 1000, 2000, 2000, 2000]);
+        backoff.reset();
+        assert_eq!(backoff.next(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn jitter_shortens() {
+        let mut backoff = Backoff::new(Schedule {
+            jitter: 0.999,
+            ..SCHEDULE
+        });
+        for max in [500, 1000, 2000, 2000] {
+            let delay = backoff.next();
+            assert!(delay <= Duration::from_millis(max));
+            assert!(delay >= Duration::from_millis(1));
+        }
     }
 }
@@ -0,0 +1,67 @@
+use clap::ValueEnum;
+
+/// How to spread public connections across clients with the same priority
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub enum Policy {
+    /// Take turns between clients
+    RoundRobin,
+    /// Prefer the client relaying the fewest connections
+    LeastActive,
+}
+
+/// A client which is currently able to relay a connection.
+#[derive(Copy, Clone, Debug)]
+pub struct Candidate<'a> {
+    pub id: &'a str,
+    pub priority: u8,
+    pub active: usize,
+}
+
+/// Chooses a client, only considering those with the best (lowest) priority.
+///
+/// Ties are broken by taking the next client after `last`, in order of id.
+pub fn choose<'a>(
+    policy: Policy,
+    candidates: &[Candidate<'a>],
+    last: Option<&str>,
+) -> Option<&'a str> {
+    let best = candidates.iter().map(|c| c.priority).min()?;
+    let mut tier = candidates
+        .iter()
+        .filter(|c| c.priority == best)
+        .collect::<Vec<_>>();
+    tier.sort_by_key(|c| c.id);
+
+    // rotate so that clients after the last choice come first
+    let next = match last {
+        Some(last) => tier.iter().position(|c| c.id > last).unwrap_or(0),
+        None => 0,
+    };
+    tier.rotate_left(next);
+
+    let chosen = match policy {
+        Policy::RoundRobin => tier.first(),
+        Policy::LeastActive => tier.iter().min_by_key(|c| c.active),
+    };
+    chosen.map(|c| c.id)
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+
+    fn c(id: &str, priority: u8, active: usize) -> Candidate<'_> {
+        Candidate { id, priority, active }
+    }
+
+    case!(empty: assert_eq!(choose(Policy::RoundRobin, &[], None), None));
+    case!(rr_first: assert_eq!(choose(Policy::RoundRobin, &[c("b", 0, 0), c("a", 0, 0)], None), Some("a")));
+    case!(rr_next: assert_eq!(choose(Policy::RoundRobin, &[c("b", 0, 0), c("a", 0, 0), c("c", 0, 0)], Some("a")), Some("b")));
+    case!(rr_wrap: assert_eq!(choose(Policy::RoundRobin, &[c("b", 0, 0), c("a", 0, 0)], Some("b")), Some("a")));
+    case!(rr_last_gone: assert_eq!(choose(Policy::RoundRobin, &[c("a", 0, 0), c("c", 0, 0)], Some("b")), Some("c")));
+    case!(rr_priority: assert_eq!(choose(Policy::RoundRobin, &[c("a", 1, 0), c("b", 0, 0)], Some("b")), Some("b")));
+    case!(la_fewest: assert_eq!(choose(Policy::LeastActive, &[c("a", 0, 5), c("b", 0, 2), c("c", 0, 3)], None), Some("b")));
+    case!(la_tie: assert_eq!(choose(Policy::LeastActive, &[c("a", 0, 1), c("b", 0, 1)], Some("a")), Some("b")));
+    case!(la_priority: assert_eq!(choose(Policy::LeastActive, &[c("a", 0, 9), c("b", 1, 0)], None), Some("a")));
+}
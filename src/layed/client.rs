@@ -1,61 +1,203 @@
-use crate::config::COPY_BUFFER_SIZE;
+use crate::capture::{Capture, Tap};
+use crate::endpoint::{self, ConnectAddr, Stream};
+use crate::layed::auth::Secret;
 use crate::layed::backoff::Backoff;
-use crate::layed::config::CLIENT_BACKOFF_SECS;
+use crate::layed::compress::{self, Compression};
+use crate::layed::config::Timing;
+use crate::layed::forward::{self, Request};
+use crate::layed::header::{self, Header, Protocol};
+use crate::layed::health::Health;
 use crate::layed::heartbeat;
+use crate::layed::identity::{self, Identity};
 use crate::layed::magic;
-use crate::tcp;
+use crate::layed::metrics::{METRICS, Track};
+use crate::layed::mux;
+use crate::layed::service::{self, Service};
+use crate::layed::udp;
+use crate::layed::version::{Capabilities, FORWARD_VERSION, Hello, IDENTITY_VERSION};
+use crate::opt::SocketAddrsFromDns;
+use crate::proxy_protocol;
+use crate::relay::{self, Closed, Timeouts};
+use crate::shutdown;
+use crate::throttle::Limits;
+use futures::future::select_all;
+use std::future::pending;
 use std::io;
-use std::net::SocketAddr;
-use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
-use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::Ordering::Relaxed;
 use tokio::io::AsyncRead;
 use tokio::io::AsyncWrite;
-use tokio::io::copy_bidirectional_with_sizes;
+use tokio::net::UdpSocket;
 use tokio::time::sleep;
 
-static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+/// Where to relay streams from the server.
+pub struct Private {
+    pub tcp: Vec<Service<ConnectAddr>>,
+    pub udp: Vec<Service<SocketAddrsFromDns>>,
+    /// PROXY protocol header to send to TCP private addresses
+    pub proxy_protocol: Option<proxy_protocol::Version>,
+    /// Bandwidth limits shared by all TCP private addresses
+    pub limits: Limits,
+    /// When to close relays to TCP private addresses
+    pub timeouts: Timeouts,
+    /// Where to capture relays to TCP private addresses
+    pub capture: Option<Capture>,
+    /// Which TCP private addresses to decline connections to
+    pub health: Health,
+}
 
 pub async fn run<Conn>(
     connect_to_gateway: impl AsyncFn() -> Result<Conn, io::Error>,
-    private_addrs: &[SocketAddr],
+    private: &Arc<Private>,
+    capabilities: Capabilities,
+    secret: Option<Secret>,
+    pool_size: u16,
+    identity: &Identity,
+    timing: &Timing,
+) -> !
+where
+    Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // nothing for the server to relay, e.g. if only forwarding local connections
+    if private.tcp.is_empty() && private.udp.is_empty() {
+        loop {
+            pending::<()>().await;
+        }
+    }
+
+    // each worker keeps one gateway waiting in the server's idle pool, and replaces it once it's used
+    let workers = (0..pool_size).map(|_| {
+        Box::pin(keep_gateway(
+            &connect_to_gateway,
+            private,
+            capabilities,
+            secret.as_ref(),
+            identity,
+            timing,
+        ))
+    });
+
+    let (never, _, _) = select_all(workers).await;
+    never
+}
+
+async fn keep_gateway<Conn>(
+    connect_to_gateway: &impl AsyncFn() -> Result<Conn, io::Error>,
+    private: &Arc<Private>,
+    capabilities: Capabilities,
+    secret: Option<&Secret>,
+    identity: &Identity,
+    timing: &Timing,
 ) -> !
 where
     Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
-    let mut backoff = Backoff::new(CLIENT_BACKOFF_SECS);
+    let health = &private.health;
+    let mut backoff = Backoff::new(timing.backoff);
 
     loop {
+        if !health.is_any_healthy() {
+            log::info!("Waiting for a private service to become healthy");
+            health.any_healthy().await;
+        }
+
         let one_round = async {
-            log::info!("Connecting to gateway");
             let mut gateway = connect_to_gateway().await?;
 
             log::info!("Sending early handshake");
-            magic::write_to(&mut gateway).await?;
+            let hello = Hello::new(capabilities);
+            let negotiated = magic::send_hello(&mut gateway, secret, hello, timing)
+                .await
+                .inspect_err(|_| {
+                    METRICS.handshake_failures.fetch_add(1, Relaxed);
+                })?;
+            log::info!(
+                "Negotiated protocol version {} (heartbeat timeout {:?})",
+                negotiated.version,
+                negotiated.heartbeat
+            );
+            if negotiated.version >= IDENTITY_VERSION {
+                identity::write_to(&mut gateway, identity, negotiated.version).await?;
+            }
+            if negotiated.version >= FORWARD_VERSION {
+                forward::write_request(&mut gateway, &Request::Idle).await?;
+            }
 
             log::info!("Waiting for end of heartbeat");
-            heartbeat::read_from(&mut gateway).await?;
+            {
+                let _idle = Track::new(&METRICS.idle_gateways);
+                tokio::select! {
+                    result = heartbeat::read_from(&mut gateway, negotiated.heartbeat) => result?,
+                    () = health.none_healthy() => {
+                        // closing the gateway removes it from the server's idle pool
+                        log::info!("Closing idle gateway, since no private services are healthy");
+                        return Ok(());
+                    }
+                }
+            }
 
             log::info!("Sending late handshake");
-            magic::write_to(&mut gateway).await?;
-
-            log::info!("Connecting to private");
-            let mut private = tcp::connect(private_addrs).await?;
-
-            log::info!("Spawning ({} active)", ACTIVE.fetch_add(1, Relaxed) + 1);
-            tokio::spawn(async move {
-                let done = copy_bidirectional_with_sizes(
-                    &mut gateway,
-                    &mut private,
-                    COPY_BUFFER_SIZE,
-                    COPY_BUFFER_SIZE,
-                )
-                .await;
-                let active = ACTIVE.fetch_sub(1, Relaxed) - 1;
-                match done {
-                    Ok((down, up)) => log::info!("Closing ({} active): {}/{}", active, down, up),
-                    Err(e) => log::info!("Closing ({} active): {}", active, e),
+            magic::write_to(&mut gateway, secret, timing.handshake).await?;
+
+            let compression = Compression::negotiate(negotiated.capabilities);
+            if let Some(mode) = compression {
+                log::info!("Compressing gateway with {:?}", mode);
+            }
+            let mut gateway = compress::wrap(gateway, compression);
+
+            if negotiated.capabilities.contains(Capabilities::MULTIPLEX) {
+                log::info!("Starting multiplexed session");
+                let (_, mut incoming) = mux::start(
+                    gateway,
+                    mux::Role::Client,
+                    negotiated.version,
+                    negotiated.heartbeat,
+                );
+                let private = Arc::clone(private);
+                let health = health.clone();
+                let timing = *timing;
+                tokio::spawn(async move {
+                    loop {
+                        let mut stream = tokio::select! {
+                            stream = incoming.accept() => match stream {
+                                Some(stream) => stream,
+                                None => break,
+                            },
+                            () = health.none_healthy() => {
+                                // dropping `incoming` tells the server to stop opening streams,
+                                // and the session closes once its active streams finish
+                                log::info!("Closing multiplexed session, since no private services are healthy");
+                                break;
+                            }
+                            () = shutdown::requested() => {
+                                log::info!("Closing multiplexed session, since the client is shutting down");
+                                break;
+                            }
+                        };
+                        let private = Arc::clone(&private);
+                        tokio::spawn(async move {
+                            match connect_to_private(&mut stream, &private, negotiated.version, &timing)
+                                .await
+                            {
+                                Ok(conn) => relay(stream, conn, &private, &timing).await,
+                                Err(e) => log::error!("Failed to connect to private: {}", e),
+                            }
+                        });
+                    }
+                });
+            } else {
+                if capabilities.contains(Capabilities::MULTIPLEX) {
+                    log::info!(
+                        "Server doesn't have multiplexing enabled, using a dedicated gateway"
+                    );
                 }
-            });
+                let conn =
+                    connect_to_private(&mut gateway, private, negotiated.version, timing).await?;
+
+                let private = Arc::clone(private);
+                let timing = *timing;
+                tokio::spawn(async move { relay(gateway, conn, &private, &timing).await });
+            }
 
             Ok::<(), io::Error>(())
         }
@@ -66,11 +208,116 @@ where
                 backoff.reset();
             }
             Err(e) => {
+                METRICS.reconnects.fetch_add(1, Relaxed);
                 log::error!("Failed: {}", e);
-                let seconds = backoff.next();
-                log::warn!("Retrying in {} seconds", seconds);
-                sleep(Duration::from_secs(u64::from(seconds))).await;
+                let delay = backoff.next();
+                log::warn!("Retrying in {:?}", delay);
+                sleep(delay).await;
             }
         }
     }
 }
+
+enum PrivateConn {
+    Tcp(Stream, Option<Tap>),
+    Udp(UdpSocket),
+}
+
+async fn connect_to_private(
+    gateway: impl AsyncRead + Unpin,
+    private: &Private,
+    version: u8,
+    timing: &Timing,
+) -> Result<PrivateConn, io::Error> {
+    let Header {
+        protocol,
+        service,
+        source,
+        destination,
+    } = header::read_from(gateway, version, timing.handshake).await?;
+
+    match protocol {
+        Protocol::Tcp => {
+            let addr = find_private(&private.tcp, protocol, &service)?;
+            if !private.health.is_healthy(&service) {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("Declining unhealthy {} service: {}", protocol, service),
+                ));
+            }
+            log::info!(
+                "Connecting to private {} ({}) for {}: {}",
+                protocol,
+                service,
+                source,
+                addr
+            );
+            let mut stream = endpoint::connect(addr).await?;
+
+            if let Some(version) = private.proxy_protocol {
+                proxy_protocol::write_to(&mut stream, version, source, destination).await?;
+            }
+
+            let tap = match &private.capture {
+                Some(capture) => capture.start(source, stream.peer_addr()?),
+                None => None,
+            };
+            Ok(PrivateConn::Tcp(stream, tap))
+        }
+        Protocol::Udp => {
+            let addrs = find_private(&private.udp, protocol, &service)?;
+            log::info!(
+                "Connecting to private {} ({}) for {}: {}",
+                protocol,
+                service,
+                source,
+                addrs.orig()
+            );
+            Ok(PrivateConn::Udp(udp::connect(addrs).await?))
+        }
+    }
+}
+
+fn find_private<'a, A>(
+    services: &'a [Service<A>],
+    protocol: Protocol,
+    service: &str,
+) -> Result<&'a A, io::Error> {
+    service::find(services, service).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Unknown {} service: {}", protocol, service),
+        )
+    })
+}
+
+async fn relay<Conn>(mut gateway: Conn, conn: PrivateConn, private: &Private, timing: &Timing)
+where
+    Conn: AsyncRead + AsyncWrite + Unpin,
+{
+    let _draining = shutdown::guard();
+    log::info!(
+        "Spawning ({} active)",
+        METRICS.active_relays.fetch_add(1, Relaxed) + 1
+    );
+    let closed = match conn {
+        PrivateConn::Tcp(conn, tap) => {
+            // data read from the gateway is on its way up to the private address
+            let (mut gateway, mut conn) = private.limits.apply(&mut gateway, conn);
+            relay::copy(&mut gateway, &mut conn, private.timeouts, tap).await
+        }
+        PrivateConn::Udp(conn) => {
+            Closed::from(udp::relay_private(gateway, conn, timing.udp_session).await)
+        }
+    };
+    let active = METRICS.active_relays.fetch_sub(1, Relaxed) - 1;
+    METRICS.bytes_to_private.fetch_add(closed.a_to_b, Relaxed);
+    METRICS.bytes_to_public.fetch_add(closed.b_to_a, Relaxed);
+    log::info!(
+        "Closing ({} active): {}/{}, {}",
+        active,
+        closed.a_to_b,
+        closed.b_to_a,
+        closed.reason
+    );
+}
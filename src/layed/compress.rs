@@ -0,0 +1,168 @@
+use crate::layed::version::Capabilities;
+use async_compression::tokio::bufread::{DeflateDecoder, ZstdDecoder};
+use async_compression::tokio::write::{DeflateEncoder, ZstdEncoder};
+use clap::ValueEnum;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{
+    AsyncRead, AsyncWrite, BufReader, Join, ReadBuf, ReadHalf, WriteHalf, join, split,
+};
+
+/// Compression applied to everything sent over a gateway, after the handshake.
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub enum Compression {
+    Deflate,
+    Zstd,
+}
+
+impl Compression {
+    /// Most preferred first.
+    const PREFERENCE: [Self; 2] = [Compression::Zstd, Compression::Deflate];
+
+    fn capability(self) -> Capabilities {
+        match self {
+            Compression::Deflate => Capabilities::DEFLATE,
+            Compression::Zstd => Capabilities::ZSTD,
+        }
+    }
+
+    /// Capabilities to announce, for the modes this side allows.
+    pub fn capabilities(allowed: &[Self]) -> Capabilities {
+        allowed
+            .iter()
+            .fold(Capabilities::NONE, |caps, mode| caps | mode.capability())
+    }
+
+    /// Most preferred mode which both sides allow, given the capabilities they agreed on.
+    pub fn negotiate(agreed: Capabilities) -> Option<Self> {
+        Self::PREFERENCE
+            .into_iter()
+            .find(|mode| agreed.contains(mode.capability()))
+    }
+}
+
+/// Gateway connection which may be compressed in both directions.
+pub enum Compressed<S> {
+    None(S),
+    Deflate(Join<DeflateDecoder<BufReader<ReadHalf<S>>>, DeflateEncoder<WriteHalf<S>>>),
+    Zstd(Join<ZstdDecoder<BufReader<ReadHalf<S>>>, ZstdEncoder<WriteHalf<S>>>),
+}
+
+pub fn wrap<S>(conn: S, mode: Option<Compression>) -> Compressed<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    match mode {
+        None => Compressed::None(conn),
+        Some(Compression::Deflate) => {
+            let (reader, writer) = split(conn);
+            Compressed::Deflate(join(
+                DeflateDecoder::new(BufReader::new(reader)),
+                DeflateEncoder::new(writer),
+            ))
+        }
+        Some(Compression::Zstd) => {
+            let (reader, writer) = split(conn);
+            Compressed::Zstd(join(
+                ZstdDecoder::new(BufReader::new(reader)),
+                ZstdEncoder::new(writer),
+            ))
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Compressed<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Compressed::None(conn) => Pin::new(conn).poll_read(cx, buf),
+            Compressed::Deflate(conn) => Pin::new(conn).poll_read(cx, buf),
+            Compressed::Zstd(conn) => Pin::new(conn).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Compressed<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Compressed::None(conn) => Pin::new(conn).poll_write(cx, buf),
+            Compressed::Deflate(conn) => Pin::new(conn).poll_write(cx, buf),
+            Compressed::Zstd(conn) => Pin::new(conn).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Compressed::None(conn) => Pin::new(conn).poll_flush(cx),
+            Compressed::Deflate(conn) => Pin::new(conn).poll_flush(cx),
+            Compressed::Zstd(conn) => Pin::new(conn).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Compressed::None(conn) => Pin::new(conn).poll_shutdown(cx),
+            Compressed::Deflate(conn) => Pin::new(conn).poll_shutdown(cx),
+            Compressed::Zstd(conn) => Pin::new(conn).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+    #[rustfmt::skip]
+    mod negotiate {
+        use super::*;
+
+        fn agreed(ours: &[Compression], theirs: &[Compression]) -> Option<Compression> {
+            Compression::negotiate(Compression::capabilities(ours) & Compression::capabilities(theirs))
+        }
+
+        case!(neither: assert_eq!(agreed(&[], &[]), None));
+        case!(only_ours: assert_eq!(agreed(&[Compression::Zstd], &[]), None));
+        case!(disjoint: assert_eq!(agreed(&[Compression::Zstd], &[Compression::Deflate]), None));
+        case!(same: assert_eq!(agreed(&[Compression::Deflate], &[Compression::Deflate]), Some(Compression::Deflate)));
+        case!(prefers_zstd: assert_eq!(agreed(&[Compression::Deflate, Compression::Zstd], &[Compression::Zstd, Compression::Deflate]), Some(Compression::Zstd)));
+    }
+
+    async fn round_trip(mode: Compression) {
+        let (a, b) = duplex(64 * 1024);
+        let mut a = wrap(a, Some(mode));
+        let mut b = wrap(b, Some(mode));
+
+        // each flushed write must arrive on its own, so interactive protocols don't stall
+        a.write_all(b"ping").await.unwrap();
+        a.flush().await.unwrap();
+        let mut buf = [0; 4];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let data = b"log line\n".repeat(1000);
+        b.write_all(&data).await.unwrap();
+        b.shutdown().await.unwrap();
+        let mut read = Vec::new();
+        a.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[tokio::test]
+    async fn deflate_round_trip() {
+        round_trip(Compression::Deflate).await;
+    }
+
+    #[tokio::test]
+    async fn zstd_round_trip() {
+        round_trip(Compression::Zstd).await;
+    }
+}
@@ -1,9 +1,27 @@
-use std::ops::RangeInclusive;
+use crate::layed::backoff::Schedule;
 use std::time::Duration;
 
-pub const QUEUE_TIMEOUT: Duration = Duration::from_secs(60);
-pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
-pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Heartbeat timeout of peers which are too old to agree on one.
+pub const LEGACY_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest heartbeat timeout to agree on, so a peer can't keep a dead gateway from being noticed for long.
+pub const MAX_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
 
-pub const SERVER_ACCEPT_BACKOFF_SECS: RangeInclusive<u8> = 1..=64;
-pub const CLIENT_BACKOFF_SECS: RangeInclusive<u8> = 1..=64;
+pub const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const MAX_CONCURRENT_SNIFFS: usize = 64;
+
+pub const UDP_SESSION_QUEUE: usize = 64;
+/// Most UDP sessions each public UDP address may have at once, since any datagram can start one.
+pub const MAX_UDP_SESSIONS: usize = 1024;
+
+/// Timeouts and backoff, from the command line.
+#[derive(Copy, Clone, Debug)]
+pub struct Timing {
+    pub handshake: Duration,
+    /// Preferred heartbeat timeout, which is agreed with each peer
+    pub heartbeat: Duration,
+    /// Retries after failing to accept connections, or to connect to a gateway
+    pub backoff: Schedule,
+    /// How long UDP sessions last without any datagrams
+    pub udp_session: Duration,
+}
@@ -0,0 +1,348 @@
+use crate::capture::{Capture, Tap};
+use crate::endpoint::Peer;
+use crate::filter::Filter;
+use crate::layed::auth::Secret;
+use crate::layed::backoff::Backoff;
+use crate::layed::config::Timing;
+use crate::layed::identity::{self, Identity};
+use crate::layed::magic;
+use crate::layed::metrics::METRICS;
+use crate::layed::service::{self, Service};
+use crate::layed::version::{Capabilities, FORWARD_VERSION, Hello};
+use crate::opt::SocketAddrsFromDns;
+use crate::relay::{self, Timeouts};
+use crate::shutdown;
+use crate::tcp;
+use crate::throttle::{Limits, ThrottleOptions};
+use futures::{StreamExt, stream};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::{sleep, timeout};
+
+const REQUEST_IDLE: u8 = 0;
+const REQUEST_FORWARD: u8 = 1;
+
+const REPLY_OK: u8 = 0;
+const REPLY_UNKNOWN_SERVICE: u8 = 1;
+const REPLY_UNREACHABLE: u8 = 2;
+
+/// Sent by the client after its identity, to say what the gateway is for.
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    /// Wait in the server's idle pool to relay public connections
+    Idle,
+    /// Immediately relay a local connection to one of the server's forward addresses
+    Forward(String),
+}
+
+pub async fn read_request(
+    mut reader: impl AsyncRead + Unpin,
+    handshake: Duration,
+) -> Result<Request, io::Error> {
+    timeout(handshake, async {
+        match reader.read_u8().await? {
+            REQUEST_IDLE => Ok(Request::Idle),
+            REQUEST_FORWARD => {
+                let len = reader.read_u8().await?;
+                let mut service = vec![0; usize::from(len)];
+                reader.read_exact(&mut service).await?;
+                let service = String::from_utf8(service)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Ok(Request::Forward(service))
+            }
+            _ => Err(io::ErrorKind::InvalidData.into()),
+        }
+    })
+    .await?
+}
+
+pub async fn write_request(
+    mut writer: impl AsyncWrite + Unpin,
+    request: &Request,
+) -> Result<(), io::Error> {
+    let mut buf = Vec::new();
+    match request {
+        Request::Idle => buf.push(REQUEST_IDLE),
+        Request::Forward(service) => {
+            let len = u8::try_from(service.len()).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "service name too long")
+            })?;
+            buf.push(REQUEST_FORWARD);
+            buf.push(len);
+            buf.extend_from_slice(service.as_bytes());
+        }
+    }
+
+    writer.write_all(&buf).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Forward addresses that clients may request, and how to relay to them, on the server.
+pub struct Forwards {
+    addrs: Vec<Service<SocketAddrsFromDns>>,
+    /// Bandwidth limits for each forward address
+    limits: HashMap<String, Limits>,
+    timeouts: Timeouts,
+}
+
+impl Forwards {
+    pub fn new(
+        addrs: Vec<Service<SocketAddrsFromDns>>,
+        throttle: &ThrottleOptions,
+        timeouts: Timeouts,
+    ) -> Self {
+        let limits = addrs
+            .iter()
+            .map(|service| (service.name.clone(), Limits::new(throttle)))
+            .collect();
+        Self {
+            addrs,
+            limits,
+            timeouts,
+        }
+    }
+}
+
+/// How to relay local connections, on the client.
+pub struct Locals {
+    pub throttle: ThrottleOptions,
+    pub timeouts: Timeouts,
+    /// Forwards are only captured by the client, since the server doesn't know where they come from
+    pub capture: Option<Capture>,
+}
+
+/// Relays a client's local connection to the forward address it requested, on the server.
+pub async fn serve(
+    mut gateway: impl AsyncRead + AsyncWrite + Unpin,
+    forwards: &Forwards,
+    service: &str,
+    identity: &Identity,
+) {
+    let _draining = shutdown::guard();
+    let done = async {
+        let Some(addrs) = service::find(&forwards.addrs, service) else {
+            write_reply(&mut gateway, REPLY_UNKNOWN_SERVICE).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Unknown forward service: {}", service),
+            ));
+        };
+
+        log::info!(
+            "Forwarding ({}) for {}: {}",
+            service,
+            identity,
+            addrs.orig()
+        );
+        let mut target = match tcp::connect(addrs).await {
+            Ok(target) => target,
+            Err(e) => {
+                write_reply(&mut gateway, REPLY_UNREACHABLE).await?;
+                return Err(e);
+            }
+        };
+        write_reply(&mut gateway, REPLY_OK).await?;
+
+        let limits = forwards.limits.get(service).cloned().unwrap_or_default();
+        // data read from the gateway is on its way to the forward address
+        let (mut gateway, mut target) = limits.apply(&mut gateway, &mut target);
+        relay(&mut gateway, &mut target, forwards.timeouts, None).await;
+        Ok(())
+    }
+    .await;
+
+    if let Err(e) = done {
+        log::info!("Forward failed: {}", e);
+    }
+}
+
+async fn write_reply(mut gateway: impl AsyncWrite + Unpin, reply: u8) -> Result<(), io::Error> {
+    gateway.write_u8(reply).await?;
+    gateway.flush().await?;
+    Ok(())
+}
+
+/// Accepts connections on local addresses, and forwards each through a new gateway, on the client.
+pub async fn run<Conn>(
+    connect_to_gateway: impl AsyncFn() -> Result<Conn, io::Error>,
+    local_addrs: &[Service<SocketAddr>],
+    filter: &Filter,
+    locals: &Locals,
+    secret: Option<&Secret>,
+    identity: &Identity,
+    timing: &Timing,
+) -> Result<(), io::Error>
+where
+    Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut local_listeners = Vec::new();
+    // bandwidth limits for each local address
+    let mut limits = HashMap::new();
+    for Service { name, addr } in local_addrs {
+        log::info!("Binding to local ({}): {}", name, addr);
+        local_listeners.push((name.clone(), TcpListener::bind(addr).await?));
+        limits.insert(name.clone(), Limits::new(&locals.throttle));
+    }
+
+    let local_connections =
+        stream::select_all(local_listeners.into_iter().map(|(name, listener)| {
+            Box::pin(stream::unfold(
+                (name, listener, filter.clone()),
+                |(name, mut listener, filter)| async move {
+                    let mut backoff = Backoff::new(timing.backoff);
+                    let stream = loop {
+                        match tcp::accept(&mut listener, &filter).await {
+                            Ok(stream) => break stream,
+                            Err(e) => {
+                                log::error!("Error accepting local connections: {}", e);
+                                let delay = backoff.next();
+                                log::warn!("Retrying in {:?}", delay);
+                                sleep(delay).await;
+                            }
+                        }
+                    };
+                    Some(((name.clone(), stream), (name, listener, filter)))
+                },
+            ))
+        }));
+
+    let (connect_to_gateway, limits) = (&connect_to_gateway, &limits);
+    local_connections
+        .for_each_concurrent(None, |(service, local)| async move {
+            let gateway =
+                request_forward(connect_to_gateway, &service, secret, identity, timing).await;
+            let gateway = match gateway {
+                Ok(gateway) => gateway,
+                Err(e) => {
+                    log::error!("Failed to forward local connection: {}", e);
+                    return;
+                }
+            };
+
+            log::info!("Forwarding local connection ({})", service);
+            let tap = match (&locals.capture, local.peer_addr(), local.local_addr()) {
+                (Some(capture), Ok(client), Ok(server)) => {
+                    capture.start(Peer::Inet(client), Peer::Inet(server))
+                }
+                _ => None,
+            };
+            let limits = limits.get(&service).cloned().unwrap_or_default();
+            // data read from the local connection is on its way to the forward address
+            let (mut local, mut gateway) = limits.apply(local, gateway);
+            let timeouts = locals.timeouts;
+            // spawned, so it can continue draining after the listeners stop for shutdown
+            tokio::spawn(async move {
+                let _draining = shutdown::guard();
+                relay(&mut local, &mut gateway, timeouts, tap).await;
+            });
+        })
+        .await;
+
+    Ok(())
+}
+
+/// Connects a new gateway, and asks the server to forward it to `service`.
+async fn request_forward<Conn>(
+    connect_to_gateway: &impl AsyncFn() -> Result<Conn, io::Error>,
+    service: &str,
+    secret: Option<&Secret>,
+    identity: &Identity,
+    timing: &Timing,
+) -> Result<Conn, io::Error>
+where
+    Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut gateway = connect_to_gateway().await?;
+
+    let negotiated =
+        magic::send_hello(&mut gateway, secret, Hello::new(Capabilities::NONE), timing)
+            .await
+            .inspect_err(|_| {
+                METRICS.handshake_failures.fetch_add(1, Relaxed);
+            })?;
+    if negotiated.version < FORWARD_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "server speaks protocol version {}, but forwarding requires at least version {} (upgrade the server)",
+                negotiated.version, FORWARD_VERSION
+            ),
+        ));
+    }
+    identity::write_to(&mut gateway, identity, negotiated.version).await?;
+    write_request(&mut gateway, &Request::Forward(service.to_string())).await?;
+
+    let reply = timeout(timing.handshake, gateway.read_u8()).await??;
+    match reply {
+        REPLY_OK => {}
+        REPLY_UNKNOWN_SERVICE => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Server has no forward address for: {}", service),
+            ));
+        }
+        REPLY_UNREACHABLE => {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!(
+                    "Server couldn't connect to forward address for: {}",
+                    service
+                ),
+            ));
+        }
+        _ => return Err(io::ErrorKind::InvalidData.into()),
+    }
+    Ok(gateway)
+}
+
+async fn relay(
+    a: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    b: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    timeouts: Timeouts,
+    tap: Option<Tap>,
+) {
+    log::info!(
+        "Spawning forward ({} active)",
+        METRICS.active_forwards.fetch_add(1, Relaxed) + 1
+    );
+    let closed = relay::copy(a, b, timeouts, tap).await;
+    let active = METRICS.active_forwards.fetch_sub(1, Relaxed) - 1;
+    log::info!(
+        "Closing forward ({} active): {}/{}, {}",
+        active,
+        closed.a_to_b,
+        closed.b_to_a,
+        closed.reason
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn requests_roundtrip() {
+        let (mut a, mut b) = duplex(1024);
+        write_request(&mut a, &Request::Idle).await.unwrap();
+        write_request(&mut a, &Request::Forward("db".to_string()))
+            .await
+            .unwrap();
+
+        let handshake = Duration::from_secs(5);
+        assert_eq!(
+            read_request(&mut b, handshake).await.unwrap(),
+            Request::Idle
+        );
+        assert_eq!(
+            read_request(&mut b, handshake).await.unwrap(),
+            Request::Forward("db".to_string())
+        );
+    }
+}
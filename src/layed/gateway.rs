@@ -0,0 +1,187 @@
+use crate::proxy::{self, Proxy};
+use crate::tcp;
+use crate::tls;
+use crate::websocket;
+use clap::ValueEnum;
+use rustls::ClientConfig;
+use std::fmt::{self, Display};
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::lookup_host;
+
+/// Any transport's connection to a gateway.
+pub trait Conn: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Conn for T {}
+
+pub type BoxConn = Box<dyn Conn>;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Transport {
+    Tcp,
+    Tls,
+    Ws,
+    Wss,
+}
+
+impl Transport {
+    pub fn is_secure(self) -> bool {
+        match self {
+            Transport::Tls | Transport::Wss => true,
+            Transport::Tcp | Transport::Ws => false,
+        }
+    }
+}
+
+/// Address of a server's gateway, optionally with a transport (e.g. `wss://example.com:443`).
+///
+/// The host isn't resolved until connecting, since a proxy may resolve it instead.
+#[derive(Clone, Debug)]
+pub struct Gateway {
+    pub transport: Option<Transport>,
+    /// `host:port`
+    pub addr: String,
+}
+
+#[derive(Debug, Error)]
+pub enum BadGateway {
+    #[error("unknown transport `{0}` (expected tcp, tls, ws, or wss)")]
+    UnknownTransport(String),
+    #[error("gateway address must be host:port")]
+    InvalidAddr,
+}
+
+impl FromStr for Gateway {
+    type Err = BadGateway;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (transport, addr) = match s.split_once("://") {
+            Some((scheme, addr)) => {
+                let transport = match scheme {
+                    "tcp" => Transport::Tcp,
+                    "tls" => Transport::Tls,
+                    "ws" => Transport::Ws,
+                    "wss" => Transport::Wss,
+                    _ => return Err(BadGateway::UnknownTransport(scheme.to_string())),
+                };
+                (Some(transport), addr)
+            }
+            None => (None, s),
+        };
+        match addr.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+            _ => return Err(BadGateway::InvalidAddr),
+        }
+        Ok(Gateway {
+            transport,
+            addr: addr.to_string(),
+        })
+    }
+}
+
+impl Display for Gateway {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.transport {
+            Some(Transport::Tcp) => write!(f, "tcp://{}", self.addr),
+            Some(Transport::Tls) => write!(f, "tls://{}", self.addr),
+            Some(Transport::Ws) => write!(f, "ws://{}", self.addr),
+            Some(Transport::Wss) => write!(f, "wss://{}", self.addr),
+            None => f.write_str(&self.addr),
+        }
+    }
+}
+
+/// How to use multiple gateways
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub enum Mode {
+    /// Keep connections to every gateway at once
+    All,
+    /// Only use the first gateway that can be reached, in the order given
+    Failover,
+}
+
+pub async fn connect(
+    gateway: &Gateway,
+    transport: Transport,
+    tls_config: Option<&Arc<ClientConfig>>,
+    proxy: Option<&Proxy>,
+) -> Result<BoxConn, io::Error> {
+    log::info!("Connecting to gateway: {}", gateway);
+    let missing_tls = || io::Error::other("TLS is not configured");
+    let stream = match proxy {
+        Some(proxy) => {
+            log::info!("Tunneling through proxy: {}", proxy.addr.orig());
+            proxy::connect(proxy, &gateway.addr).await?
+        }
+        None => {
+            let addrs = lookup_host(&gateway.addr).await?.collect::<Vec<_>>();
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "Resolved to zero addresses",
+                ));
+            }
+            tcp::connect(&addrs).await?
+        }
+    };
+    match transport {
+        Transport::Tcp => Ok(Box::new(stream)),
+        Transport::Tls => {
+            let config = tls_config.ok_or_else(missing_tls)?;
+            let server_name = tls::server_name(&gateway.addr)?;
+            Ok(Box::new(tls::connect(stream, &server_name, config).await?))
+        }
+        Transport::Ws | Transport::Wss => {
+            let (scheme, tls_config) = match transport {
+                Transport::Wss => ("wss", Some(tls_config.ok_or_else(missing_tls)?)),
+                _ => ("ws", None),
+            };
+            let uri = http::uri::Builder::new()
+                .scheme(scheme)
+                .authority(gateway.addr.as_str())
+                .path_and_query("/ws/")
+                .build()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            Ok(Box::new(
+                websocket::connect(&uri, stream, tls_config).await?,
+            ))
+        }
+    }
+}
+
+/// Connects to the first gateway that can be reached, in order.
+pub async fn connect_first(
+    gateways: &[(Gateway, Transport)],
+    tls_config: Option<&Arc<ClientConfig>>,
+    proxy: Option<&Proxy>,
+) -> Result<BoxConn, io::Error> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "No gateways");
+    for (gateway, transport) in gateways {
+        match connect(gateway, *transport, tls_config, proxy).await {
+            Ok(conn) => return Ok(conn),
+            Err(e) => {
+                log::warn!("Gateway {} unavailable: {}", gateway, e);
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+
+    case!(bare: assert!(matches!(Gateway::from_str("127.0.0.1:8080"), Ok(Gateway { transport: None, .. }))));
+    case!(tcp: assert!(matches!(Gateway::from_str("tcp://127.0.0.1:8080"), Ok(Gateway { transport: Some(Transport::Tcp), .. }))));
+    case!(wss: assert!(matches!(Gateway::from_str("wss://127.0.0.1:443"), Ok(Gateway { transport: Some(Transport::Wss), .. }))));
+    case!(unknown: assert!(matches!(Gateway::from_str("udp://127.0.0.1:8080"), Err(BadGateway::UnknownTransport(s)) if s == "udp")));
+    case!(unresolved: assert!(matches!(Gateway::from_str("tls://gateway.invalid:443"), Ok(Gateway { addr, .. }) if addr == "gateway.invalid:443")));
+    case!(bad_addr: assert!(matches!(Gateway::from_str("ws://nope"), Err(BadGateway::InvalidAddr))));
+    case!(bad_port: assert!(matches!(Gateway::from_str("example.com:http"), Err(BadGateway::InvalidAddr))));
+    case!(display: assert_eq!(Gateway::from_str("ws://127.0.0.1:80").unwrap().to_string(), "ws://127.0.0.1:80"));
+}
@@ -0,0 +1,185 @@
+use crate::endpoint::Peer;
+use crate::layed::version::{LEGACY_VERSION, UDP_VERSION, UNIX_VERSION};
+use std::fmt::{self, Display};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+const FAMILY_V4: u8 = 4;
+const FAMILY_V6: u8 = 6;
+const FAMILY_UNIX: u8 = 0;
+
+const PROTOCOL_TCP: u8 = 0;
+const PROTOCOL_UDP: u8 = 1;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => f.write_str("TCP"),
+            Protocol::Udp => f.write_str("UDP"),
+        }
+    }
+}
+
+impl Protocol {
+    /// Oldest protocol version that can relay this protocol.
+    pub fn min_version(self) -> u8 {
+        match self {
+            Protocol::Tcp => LEGACY_VERSION,
+            Protocol::Udp => UDP_VERSION,
+        }
+    }
+}
+
+/// Sent by the server at the start of each relayed stream, to describe where it should go.
+pub struct Header {
+    pub protocol: Protocol,
+    pub service: String,
+    /// Remote end of the public connection
+    pub source: Peer,
+    /// Local end that the public connection was accepted on
+    pub destination: Peer,
+}
+
+pub async fn read_from(
+    mut reader: impl AsyncRead + Unpin,
+    version: u8,
+    handshake: Duration,
+) -> Result<Header, io::Error> {
+    timeout(handshake, async {
+        let protocol = if version >= UDP_VERSION {
+            match reader.read_u8().await? {
+                PROTOCOL_TCP => Protocol::Tcp,
+                PROTOCOL_UDP => Protocol::Udp,
+                _ => return Err(io::ErrorKind::InvalidData.into()),
+            }
+        } else {
+            Protocol::Tcp
+        };
+        let len = reader.read_u8().await?;
+        let mut service = vec![0; usize::from(len)];
+        reader.read_exact(&mut service).await?;
+        let service = String::from_utf8(service)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let source = read_addr(&mut reader).await?;
+        let destination = read_addr(&mut reader).await?;
+        Ok(Header {
+            protocol,
+            service,
+            source,
+            destination,
+        })
+    })
+    .await?
+}
+
+pub async fn write_to(
+    mut writer: impl AsyncWrite + Unpin,
+    header: &Header,
+    version: u8,
+) -> Result<(), io::Error> {
+    if version < header.protocol.min_version() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "peer is too old to relay this protocol",
+        ));
+    }
+    let len = u8::try_from(header.service.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "service name too long"))?;
+
+    let mut buf = Vec::with_capacity(2 + header.service.len() + 2 * 19);
+    if version >= UDP_VERSION {
+        buf.push(match header.protocol {
+            Protocol::Tcp => PROTOCOL_TCP,
+            Protocol::Udp => PROTOCOL_UDP,
+        });
+    }
+    buf.push(len);
+    buf.extend_from_slice(header.service.as_bytes());
+    write_addr(&mut buf, header.source, version);
+    write_addr(&mut buf, header.destination, version);
+
+    writer.write_all(&buf).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn read_addr(mut reader: impl AsyncRead + Unpin) -> Result<Peer, io::Error> {
+    let ip = match reader.read_u8().await? {
+        FAMILY_V4 => {
+            let mut octets = [0; 4];
+            reader.read_exact(&mut octets).await?;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        FAMILY_V6 => {
+            let mut octets = [0; 16];
+            reader.read_exact(&mut octets).await?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        FAMILY_UNIX => return Ok(Peer::Unix),
+        _ => return Err(io::ErrorKind::InvalidData.into()),
+    };
+    let port = reader.read_u16().await?;
+    Ok(Peer::Inet(SocketAddr::new(ip, port)))
+}
+
+/// Older peers can only read socket addresses, so unix sockets are sent to them as `0.0.0.0:0`.
+fn write_addr(buf: &mut Vec<u8>, peer: Peer, version: u8) {
+    let addr = match peer {
+        Peer::Inet(addr) => addr,
+        Peer::Unix if version >= UNIX_VERSION => return buf.push(FAMILY_UNIX),
+        Peer::Unix => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
+    };
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(FAMILY_V4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(FAMILY_V6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    async fn roundtrip(source: Peer, version: u8) -> Peer {
+        let (mut a, mut b) = duplex(1024);
+        let header = Header {
+            protocol: Protocol::Tcp,
+            service: "web".to_string(),
+            source,
+            destination: Peer::Inet("10.0.0.2:80".parse().unwrap()),
+        };
+        write_to(&mut a, &header, version).await.unwrap();
+        let read = read_from(&mut b, version, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(read.destination, header.destination);
+        read.source
+    }
+
+    #[tokio::test]
+    async fn unix_peers_roundtrip() {
+        let inet = Peer::Inet("[2001:db8::1]:5678".parse().unwrap());
+        assert_eq!(roundtrip(inet, UNIX_VERSION).await, inet);
+        assert_eq!(roundtrip(Peer::Unix, UNIX_VERSION).await, Peer::Unix);
+        assert_eq!(
+            roundtrip(Peer::Unix, UNIX_VERSION - 1).await,
+            Peer::Inet("0.0.0.0:0".parse().unwrap())
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use crate::endpoint::{self, ConnectAddr};
+use crate::layed::config::HEALTH_CHECK_TIMEOUT;
+use crate::layed::service::Service;
+use crate::proxy_protocol;
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::future::pending;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::watch;
+use tokio::time::{interval, timeout};
+
+/// Most bytes of the status line to accept from an HTTP health check.
+const MAX_STATUS_LINE_LEN: usize = 1024;
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub enum Probe {
+    /// Open a TCP (or unix socket) connection
+    Tcp,
+    /// Send an HTTP GET request, and expect a 2xx or 3xx response
+    Http,
+}
+
+/// Which private services are currently passing their health check.
+#[derive(Clone)]
+pub struct Health(watch::Receiver<HashMap<String, bool>>);
+
+impl Health {
+    /// Always healthy, for when no health check is configured.
+    pub fn always() -> Self {
+        let (_, rx) = watch::channel(HashMap::new());
+        Self(rx)
+    }
+
+    /// Whether the service is healthy, which it is if it isn't probed (e.g. UDP services).
+    pub fn is_healthy(&self, service: &str) -> bool {
+        self.0.borrow().get(service).copied().unwrap_or(true)
+    }
+
+    /// Whether any service is healthy, in which case gateways are offered for all of them.
+    pub fn is_any_healthy(&self) -> bool {
+        any_healthy(&self.0.borrow())
+    }
+
+    /// Completes once any service is healthy.
+    pub async fn any_healthy(&self) {
+        let mut rx = self.0.clone();
+        if rx.wait_for(any_healthy).await.is_err() {
+            pending().await
+        }
+    }
+
+    /// Completes once no services are healthy.
+    pub async fn none_healthy(&self) {
+        let mut rx = self.0.clone();
+        if rx
+            .wait_for(|services| !any_healthy(services))
+            .await
+            .is_err()
+        {
+            pending().await
+        }
+    }
+}
+
+fn any_healthy(services: &HashMap<String, bool>) -> bool {
+    services.is_empty() || services.values().any(|&healthy| healthy)
+}
+
+/// Spawns a task to periodically probe every service.
+///
+/// Services start out unhealthy, until the first round of probes completes.
+pub fn spawn(
+    probe: Probe,
+    path: String,
+    proxy_protocol: Option<proxy_protocol::Version>,
+    services: Vec<Service<ConnectAddr>>,
+    every: Duration,
+) -> Health {
+    let (tx, rx) = watch::channel(
+        services
+            .iter()
+            .map(|service| (service.name.clone(), false))
+            .collect::<HashMap<_, _>>(),
+    );
+
+    tokio::spawn(async move {
+        let mut every = interval(every);
+        let mut first = true;
+        loop {
+            every.tick().await;
+
+            let mut health = HashMap::with_capacity(services.len());
+            for Service { name, addr } in &services {
+                let result = check(probe, &path, proxy_protocol, addr).await;
+                let healthy = result.is_ok();
+                if first || healthy != tx.borrow().get(name).copied().unwrap_or(false) {
+                    match result {
+                        Ok(()) => log::info!("Private service {} is healthy", name),
+                        Err(e) => log::warn!(
+                            "Private service {} is unhealthy, declining its connections: {}: {}",
+                            name,
+                            addr,
+                            e
+                        ),
+                    }
+                }
+                health.insert(name.clone(), healthy);
+            }
+            first = false;
+
+            if tx.send(health).is_err() {
+                break;
+            }
+        }
+    });
+
+    Health(rx)
+}
+
+async fn check(
+    probe: Probe,
+    path: &str,
+    proxy_protocol: Option<proxy_protocol::Version>,
+    addr: &ConnectAddr,
+) -> Result<(), io::Error> {
+    timeout(HEALTH_CHECK_TIMEOUT, async {
+        let mut stream = endpoint::connect(addr).await?;
+        // the service expects a header on every connection, and would reject the probe without one
+        if let Some(version) = proxy_protocol {
+            proxy_protocol::write_local_to(&mut stream, version).await?;
+        }
+        match probe {
+            Probe::Tcp => Ok(()),
+            Probe::Http => {
+                stream
+                    .write_all(http_request(path, host(addr)).as_bytes())
+                    .await?;
+
+                let mut status_line = Vec::new();
+                while !status_line.ends_with(b"\r\n") {
+                    if status_line.len() >= MAX_STATUS_LINE_LEN {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "status line too long",
+                        ));
+                    }
+                    status_line.push(stream.read_u8().await?);
+                }
+                let status_line = String::from_utf8_lossy(&status_line);
+                check_status(status_line.trim_end())
+            }
+        }
+    })
+    .await?
+}
+
+/// Host header to send, which is arbitrary for unix sockets, so use what e.g. curl sends.
+fn host(addr: &ConnectAddr) -> &str {
+    match addr {
+        ConnectAddr::Tcp(addrs) => addrs.orig(),
+        ConnectAddr::Unix(_) => "localhost",
+    }
+}
+
+fn http_request(path: &str, host: &str) -> String {
+    format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, host
+    )
+}
+
+fn check_status(status_line: &str) -> Result<(), io::Error> {
+    match status_line.split(' ').nth(1) {
+        Some(status) if status.starts_with('2') || status.starts_with('3') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "health check failed: {}",
+            status_line
+        ))),
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+
+    fn health(services: &[(&str, bool)]) -> Health {
+        let services = services.iter().map(|&(name, healthy)| (name.to_string(), healthy)).collect();
+        Health(watch::channel(services).1)
+    }
+
+    case!(mixed_serves_healthy: assert!(health(&[("web", true), ("db", false)]).is_healthy("web")));
+    case!(mixed_declines_unhealthy: assert!(!health(&[("web", true), ("db", false)]).is_healthy("db")));
+    case!(mixed_keeps_gateways: assert!(health(&[("web", true), ("db", false)]).is_any_healthy()));
+    case!(all_unhealthy_withholds_gateways: assert!(!health(&[("web", false), ("db", false)]).is_any_healthy()));
+    case!(unprobed_is_healthy: assert!(health(&[("web", false)]).is_healthy("dns")));
+    case!(always_healthy: assert!(Health::always().is_any_healthy()));
+
+    case!(request: assert_eq!(http_request("/health", "localhost:8080"), "GET /health HTTP/1.1\r\nHost: localhost:8080\r\nConnection: close\r\n\r\n"));
+
+    case!(status_ok: assert!(check_status("HTTP/1.1 200 OK").is_ok()));
+    case!(status_redirect: assert!(check_status("HTTP/1.0 302 Found").is_ok()));
+    case!(status_error: assert!(check_status("HTTP/1.1 503 Service Unavailable").is_err()));
+    case!(status_garbage: assert!(check_status("SSH-2.0-OpenSSH_9.6").is_err()));
+}
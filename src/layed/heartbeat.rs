@@ -1,16 +1,20 @@
-use crate::layed::config::HEARTBEAT_TIMEOUT;
 use std::convert::Infallible;
 use std::io;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::time::{interval, timeout};
 
 const HEARTBEAT: [u8; 1] = [0xdd];
 const EXIT: [u8; 1] = [0x1c];
 
-pub async fn read_from(mut reader: impl AsyncRead + Unpin) -> Result<(), io::Error> {
+/// Waits for heartbeats until the final one, failing if none arrive within the agreed `heartbeat` timeout.
+pub async fn read_from(
+    mut reader: impl AsyncRead + Unpin,
+    heartbeat: Duration,
+) -> Result<(), io::Error> {
     let mut buf = [0; 1];
     loop {
-        timeout(HEARTBEAT_TIMEOUT, reader.read_exact(&mut buf)).await??;
+        timeout(heartbeat, reader.read_exact(&mut buf)).await??;
         match buf {
             HEARTBEAT => continue,
             EXIT => return Ok(()),
@@ -19,12 +23,16 @@ pub async fn read_from(mut reader: impl AsyncRead + Unpin) -> Result<(), io::Err
     }
 }
 
-pub async fn write_forever(mut writer: impl AsyncWrite + Unpin) -> Result<Infallible, io::Error> {
-    let mut heartbeat = interval(HEARTBEAT_TIMEOUT / 2);
+/// Sends heartbeats twice per agreed `heartbeat` timeout, so one can be delayed without the peer timing out.
+pub async fn write_forever(
+    mut writer: impl AsyncWrite + Unpin,
+    heartbeat: Duration,
+) -> Result<Infallible, io::Error> {
+    let mut every = interval(heartbeat / 2);
     loop {
         writer.write_all(&HEARTBEAT).await?;
         writer.flush().await?;
-        heartbeat.tick().await;
+        every.tick().await;
     }
 }
 
@@ -0,0 +1,128 @@
+use crate::layed::version::HOSTNAMES_VERSION;
+use std::fmt::{self, Display};
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+/// Sent by the client after the early handshake, so the server can tell clients apart.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    pub id: String,
+    /// Lower values are preferred, with higher values only used as backups.
+    pub priority: u8,
+    /// Hostnames to route public connections by, where `*.` matches any subdomain.
+    ///
+    /// Clients without any hostnames receive connections that don't match another client.
+    pub hostnames: Vec<String>,
+}
+
+impl Identity {
+    /// Identity assigned to clients which are too old to send one.
+    pub fn unidentified() -> Self {
+        Self {
+            id: String::new(),
+            priority: 0,
+            hostnames: Vec::new(),
+        }
+    }
+
+    pub fn serves(&self, hostname: &str) -> bool {
+        self.hostnames
+            .iter()
+            .any(|pattern| match pattern.strip_prefix("*.") {
+                Some(suffix) => hostname
+                    .strip_suffix(suffix)
+                    .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+                None => pattern == hostname,
+            })
+    }
+}
+
+impl Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.id.as_str() {
+            "" => f.write_str("unidentified client"),
+            id => write!(f, "client {}", id),
+        }
+    }
+}
+
+pub async fn read_from(
+    mut reader: impl AsyncRead + Unpin,
+    version: u8,
+    handshake: Duration,
+) -> Result<Identity, io::Error> {
+    timeout(handshake, async {
+        let id = read_string(&mut reader).await?;
+        let priority = reader.read_u8().await?;
+        let mut hostnames = Vec::new();
+        if version >= HOSTNAMES_VERSION {
+            let count = reader.read_u8().await?;
+            for _ in 0..count {
+                hostnames.push(read_string(&mut reader).await?);
+            }
+        }
+        Ok(Identity {
+            id,
+            priority,
+            hostnames,
+        })
+    })
+    .await?
+}
+
+async fn read_string(mut reader: impl AsyncRead + Unpin) -> Result<String, io::Error> {
+    let len = reader.read_u8().await?;
+    let mut s = vec![0; usize::from(len)];
+    reader.read_exact(&mut s).await?;
+    String::from_utf8(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub async fn write_to(
+    mut writer: impl AsyncWrite + Unpin,
+    identity: &Identity,
+    version: u8,
+) -> Result<(), io::Error> {
+    let mut buf = Vec::new();
+    write_string(&mut buf, &identity.id, "client id too long")?;
+    buf.push(identity.priority);
+    if version >= HOSTNAMES_VERSION {
+        let count = u8::try_from(identity.hostnames.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many hostnames"))?;
+        buf.push(count);
+        for hostname in &identity.hostnames {
+            write_string(&mut buf, hostname, "hostname too long")?;
+        }
+    }
+
+    writer.write_all(&buf).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str, too_long: &'static str) -> Result<(), io::Error> {
+    let len =
+        u8::try_from(s.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, too_long))?;
+    buf.push(len);
+    buf.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+
+    fn serving(hostnames: &[&str]) -> Identity {
+        Identity { hostnames: hostnames.iter().map(|h| h.to_string()).collect(), ..Identity::unidentified() }
+    }
+
+    case!(exact: assert!(serving(&["example.com"]).serves("example.com")));
+    case!(exact_other: assert!(!serving(&["example.com"]).serves("www.example.com")));
+    case!(wildcard: assert!(serving(&["*.example.com"]).serves("www.example.com")));
+    case!(wildcard_nested: assert!(serving(&["*.example.com"]).serves("a.b.example.com")));
+    case!(wildcard_bare: assert!(!serving(&["*.example.com"]).serves("example.com")));
+    case!(wildcard_suffix: assert!(!serving(&["*.example.com"]).serves("badexample.com")));
+    case!(none: assert!(!serving(&[]).serves("example.com")));
+}
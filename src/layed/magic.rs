@@ -1,21 +1,219 @@
-use crate::layed::config::HANDSHAKE_TIMEOUT;
+use crate::layed::auth::{self, Secret};
+use crate::layed::config::{LEGACY_HEARTBEAT_TIMEOUT, MAX_HEARTBEAT_TIMEOUT, Timing};
+use crate::layed::version::{self, Capabilities, HEARTBEAT_VERSION, Hello, Negotiated};
 use std::io;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::time::timeout;
 
 const MAGIC: [u8; 1] = [42];
+/// Sent instead of `MAGIC` to start the early handshake, by clients which negotiate a protocol version.
+const HELLO: u8 = 43;
 
-pub async fn read_from(mut reader: impl AsyncRead + Unpin) -> Result<(), io::Error> {
-    let mut buf = [0; 1];
-    timeout(HANDSHAKE_TIMEOUT, reader.read_exact(&mut buf)).await??;
-    match buf {
-        MAGIC => Ok(()),
-        _ => Err(io::ErrorKind::InvalidData.into()),
+/// Server side of the early handshake.
+///
+/// Clients which only send the bare magic byte are accepted as legacy peers, unless a shared secret is required.
+pub async fn accept_hello(
+    mut conn: impl AsyncRead + AsyncWrite + Unpin,
+    secret: Option<&Secret>,
+    ours: Hello,
+    timing: &Timing,
+) -> Result<Negotiated, io::Error> {
+    let ours = with_auth(ours, secret);
+    timeout(timing.handshake, async {
+        let negotiated = match conn.read_u8().await? {
+            HELLO => {
+                let theirs = read_hello(&mut conn).await?;
+                // always reply, so the client can also tell why negotiation failed
+                write_hello(&mut conn, ours).await?;
+                let negotiated = version::negotiate(ours, theirs)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Unsupported, e))?;
+                agree_heartbeat(&mut conn, negotiated, timing.heartbeat).await?
+            }
+            byte if [byte] == MAGIC => match secret {
+                Some(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "legacy client can't authenticate with a shared secret (upgrade the client)",
+                    ));
+                }
+                None => return Ok(Negotiated::LEGACY),
+            },
+            _ => return Err(io::ErrorKind::InvalidData.into()),
+        };
+        if let Some(secret) = secret {
+            auth::challenge(&mut conn, secret).await?;
+        }
+        Ok(negotiated)
+    })
+    .await?
+}
+
+/// Client side of the early handshake.
+pub async fn send_hello(
+    mut conn: impl AsyncRead + AsyncWrite + Unpin,
+    secret: Option<&Secret>,
+    ours: Hello,
+    timing: &Timing,
+) -> Result<Negotiated, io::Error> {
+    let ours = with_auth(ours, secret);
+    conn.write_all(&[HELLO]).await?;
+    write_hello(&mut conn, ours).await?;
+
+    let theirs = match timeout(timing.handshake, read_hello(&mut conn)).await? {
+        Ok(theirs) => theirs,
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset
+            ) =>
+        {
+            return Err(io::Error::new(
+                e.kind(),
+                "server closed the connection during the handshake (it may be too old to negotiate a protocol version)",
+            ));
+        }
+        Err(e) => return Err(e),
+    };
+    let negotiated = version::negotiate(ours, theirs)
+        .map_err(|e| io::Error::new(io::ErrorKind::Unsupported, e))?;
+    let negotiated = timeout(
+        timing.handshake,
+        agree_heartbeat(&mut conn, negotiated, timing.heartbeat),
+    )
+    .await??;
+
+    if let Some(secret) = secret {
+        timeout(timing.handshake, auth::respond(&mut conn, secret)).await??;
+    }
+    Ok(negotiated)
+}
+
+fn with_auth(hello: Hello, secret: Option<&Secret>) -> Hello {
+    Hello {
+        capabilities: hello
+            .capabilities
+            .with(Capabilities::AUTH, secret.is_some()),
+        ..hello
     }
 }
 
-pub async fn write_to(mut writer: impl AsyncWrite + Unpin) -> Result<(), io::Error> {
-    writer.write_all(&MAGIC).await?;
+async fn read_hello(mut reader: impl AsyncRead + Unpin) -> Result<Hello, io::Error> {
+    let version = reader.read_u8().await?;
+    let capabilities = Capabilities::from_bits(reader.read_u32().await?);
+    Ok(Hello {
+        version,
+        capabilities,
+    })
+}
+
+async fn write_hello(mut writer: impl AsyncWrite + Unpin, hello: Hello) -> Result<(), io::Error> {
+    let mut buf = [0; 5];
+    buf[0] = hello.version;
+    buf[1..].copy_from_slice(&hello.capabilities.bits().to_be_bytes());
+    writer.write_all(&buf).await?;
     writer.flush().await?;
     Ok(())
 }
+
+/// Agrees on the longer of both sides' heartbeat timeouts, so neither times out the other's heartbeats.
+async fn agree_heartbeat(
+    mut conn: impl AsyncRead + AsyncWrite + Unpin,
+    negotiated: Negotiated,
+    ours: Duration,
+) -> Result<Negotiated, io::Error> {
+    if negotiated.version < HEARTBEAT_VERSION {
+        if ours != LEGACY_HEARTBEAT_TIMEOUT {
+            log::warn!(
+                "Peer is too old to agree on a heartbeat timeout, using the default of {:?}",
+                LEGACY_HEARTBEAT_TIMEOUT
+            );
+        }
+        return Ok(negotiated);
+    }
+
+    conn.write_u32(u32::try_from(ours.as_millis()).unwrap_or(u32::MAX))
+        .await?;
+    conn.flush().await?;
+    let theirs = Duration::from_millis(u64::from(conn.read_u32().await?));
+    if theirs > MAX_HEARTBEAT_TIMEOUT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "peer's heartbeat timeout of {:?} is longer than the maximum of {:?}",
+                theirs, MAX_HEARTBEAT_TIMEOUT
+            ),
+        ));
+    }
+    Ok(Negotiated {
+        heartbeat: ours.max(theirs),
+        ..negotiated
+    })
+}
+
+/// Server side of the late handshake.
+pub async fn read_from(
+    mut conn: impl AsyncRead + AsyncWrite + Unpin,
+    secret: Option<&Secret>,
+    handshake: Duration,
+) -> Result<(), io::Error> {
+    timeout(handshake, async {
+        let mut buf = [0; 1];
+        conn.read_exact(&mut buf).await?;
+        match buf {
+            MAGIC => {}
+            _ => return Err(io::ErrorKind::InvalidData.into()),
+        }
+        if let Some(secret) = secret {
+            auth::challenge(&mut conn, secret).await?;
+        }
+        Ok(())
+    })
+    .await?
+}
+
+/// Client side of the late handshake.
+pub async fn write_to(
+    mut conn: impl AsyncRead + AsyncWrite + Unpin,
+    secret: Option<&Secret>,
+    handshake: Duration,
+) -> Result<(), io::Error> {
+    conn.write_all(&MAGIC).await?;
+    conn.flush().await?;
+    if let Some(secret) = secret {
+        timeout(handshake, auth::respond(&mut conn, secret)).await??;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layed::version::PROTOCOL_VERSION;
+    use tokio::io::duplex;
+
+    async fn agree_with(theirs: Duration) -> Result<Negotiated, io::Error> {
+        let (mut ours, mut peer) = duplex(64);
+        let millis = u32::try_from(theirs.as_millis()).unwrap();
+        peer.write_u32(millis).await.unwrap();
+        let negotiated = Negotiated {
+            version: PROTOCOL_VERSION,
+            ..Negotiated::LEGACY
+        };
+        agree_heartbeat(&mut ours, negotiated, Duration::from_secs(10)).await
+    }
+
+    #[tokio::test]
+    async fn agrees_on_longer_heartbeat() {
+        let negotiated = agree_with(Duration::from_secs(30)).await.unwrap();
+        assert_eq!(negotiated.heartbeat, Duration::from_secs(30));
+        let negotiated = agree_with(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(negotiated.heartbeat, Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn rejects_excessive_heartbeat() {
+        let e = agree_with(MAX_HEARTBEAT_TIMEOUT * 2).await.err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,175 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::header::{CONTENT_TYPE, HeaderValue};
+use hyper::{Method, Request, Response, StatusCode};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed};
+
+/// Counters for the admin endpoint, shared by everything running in this process.
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    /// Gateways which completed the early handshake and are waiting in heartbeat
+    pub idle_gateways: AtomicUsize,
+    /// Public connections waiting for a gateway
+    pub queued_connections: AtomicUsize,
+    /// Public connections being relayed to private addresses
+    pub active_relays: AtomicUsize,
+    /// Local connections being forwarded to the server's forward addresses
+    pub active_forwards: AtomicUsize,
+    /// Bytes sent towards private addresses, counted when each relay closes
+    pub bytes_to_private: AtomicU64,
+    /// Bytes sent towards public peers, counted when each relay closes
+    pub bytes_to_public: AtomicU64,
+    /// Early handshakes which failed, on either side
+    pub handshake_failures: AtomicU64,
+    /// Times the client retried after failing to connect to, or losing, a gateway
+    pub reconnects: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            idle_gateways: AtomicUsize::new(0),
+            queued_connections: AtomicUsize::new(0),
+            active_relays: AtomicUsize::new(0),
+            active_forwards: AtomicUsize::new(0),
+            bytes_to_private: AtomicU64::new(0),
+            bytes_to_public: AtomicU64::new(0),
+            handshake_failures: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+        }
+    }
+
+    fn snapshot(&self) -> [(&'static str, Kind, &'static str, u64); 8] {
+        [
+            (
+                "idle_gateways",
+                Kind::Gauge,
+                "Gateways waiting in heartbeat",
+                self.idle_gateways.load(Relaxed) as u64,
+            ),
+            (
+                "queued_connections",
+                Kind::Gauge,
+                "Public connections waiting for a gateway",
+                self.queued_connections.load(Relaxed) as u64,
+            ),
+            (
+                "active_relays",
+                Kind::Gauge,
+                "Connections being relayed",
+                self.active_relays.load(Relaxed) as u64,
+            ),
+            (
+                "active_forwards",
+                Kind::Gauge,
+                "Local connections being forwarded",
+                self.active_forwards.load(Relaxed) as u64,
+            ),
+            (
+                "bytes_to_private",
+                Kind::Counter,
+                "Bytes sent towards private addresses, counted when each relay closes",
+                self.bytes_to_private.load(Relaxed),
+            ),
+            (
+                "bytes_to_public",
+                Kind::Counter,
+                "Bytes sent towards public peers, counted when each relay closes",
+                self.bytes_to_public.load(Relaxed),
+            ),
+            (
+                "handshake_failures",
+                Kind::Counter,
+                "Early handshakes which failed",
+                self.handshake_failures.load(Relaxed),
+            ),
+            (
+                "reconnects",
+                Kind::Counter,
+                "Times the client retried connecting to a gateway",
+                self.reconnects.load(Relaxed),
+            ),
+        ]
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Kind {
+    Gauge,
+    Counter,
+}
+
+/// Increments a gauge, and decrements it again when dropped.
+pub struct Track(&'static AtomicUsize);
+
+impl Track {
+    pub fn new(gauge: &'static AtomicUsize) -> Self {
+        gauge.fetch_add(1, Relaxed);
+        Self(gauge)
+    }
+}
+
+impl Drop for Track {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Relaxed);
+    }
+}
+
+pub async fn respond_to_request(req: Request<Incoming>, _state: &()) -> Response<Full<Bytes>> {
+    let (body, content_type) = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/") => (to_json(&METRICS.snapshot()), "application/json"),
+        (&Method::GET, "/metrics") => (
+            to_prometheus(&METRICS.snapshot()),
+            "text/plain; version=0.0.4",
+        ),
+        (&Method::GET, _) => return status(StatusCode::NOT_FOUND),
+        _ => return status(StatusCode::METHOD_NOT_ALLOWED),
+    };
+    let mut resp = Response::new(Full::new(Bytes::from(body)));
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    resp
+}
+
+fn status(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut resp = Response::new(Full::default());
+    *resp.status_mut() = status;
+    resp
+}
+
+fn to_json(metrics: &[(&str, Kind, &str, u64)]) -> String {
+    let fields = metrics
+        .iter()
+        .map(|(name, _, _, value)| format!("\"{}\":{}", name, value))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn to_prometheus(metrics: &[(&str, Kind, &str, u64)]) -> String {
+    let mut out = String::new();
+    for &(name, kind, help, value) in metrics {
+        let (kind, suffix) = match kind {
+            Kind::Gauge => ("gauge", ""),
+            Kind::Counter => ("counter", "_total"),
+        };
+        let _ = writeln!(out, "# HELP re_layed_{}{} {}", name, suffix, help);
+        let _ = writeln!(out, "# TYPE re_layed_{}{} {}", name, suffix, kind);
+        let _ = writeln!(out, "re_layed_{}{} {}", name, suffix, value);
+    }
+    out
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+
+    const SAMPLE: [(&str, Kind, &str, u64); 2] = [("idle_gateways", Kind::Gauge, "Idle", 2), ("reconnects", Kind::Counter, "Retries", 5)];
+
+    case!(json: assert_eq!(to_json(&SAMPLE), r#"{"idle_gateways":2,"reconnects":5}"#));
+    case!(json_empty: assert_eq!(to_json(&[]), "{}"));
+    case!(prometheus: assert_eq!(to_prometheus(&SAMPLE), "# HELP re_layed_idle_gateways Idle\n# TYPE re_layed_idle_gateways gauge\nre_layed_idle_gateways 2\n# HELP re_layed_reconnects_total Retries\n# TYPE re_layed_reconnects_total counter\nre_layed_reconnects_total 5\n"));
+}
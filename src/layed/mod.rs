@@ -1,60 +1,440 @@
-use crate::tcp;
+use crate::capacity::Capacity;
+use crate::capture::Capture;
+use crate::endpoint::ListenAddr;
+use crate::filter::Filter;
+use crate::http;
+use crate::layed::auth::Secret;
+use crate::layed::compress::Compression;
+use crate::layed::version::Capabilities;
+use crate::throttle::Limits;
+use crate::tls;
 use crate::websocket;
+use futures::future::{self, Either, select, select_all};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::future::pending;
+use std::pin::pin;
+use std::sync::Arc;
+use std::{fs, io};
+use tokio_rustls::TlsAcceptor;
 
+mod auth;
 mod backoff;
+mod balance;
 mod client;
+mod compress;
 mod config;
+mod forward;
+mod gateway;
+mod header;
+mod health;
 mod heartbeat;
+mod identity;
 mod magic;
+mod metrics;
+mod mux;
 pub mod opt;
+mod pool;
 mod server;
-mod stream;
+mod service;
+mod sniff;
+mod udp;
+mod version;
 
-pub async fn main(options: opt::Options) -> Result<(), std::io::Error> {
+pub async fn main(options: opt::Options) -> Result<(), io::Error> {
     let opt::Options { mode } = options;
 
     match mode {
         opt::Mode::Server {
             gateway,
             public,
+            udp,
+            forward,
             websocket,
+            tls_cert,
+            tls_key,
+            multiplex,
+            compression,
+            balance,
+            route_by_host,
+            admin,
+            queue_timeout,
+            gateway_allow,
+            gateway_deny,
+            filter,
+            capacity,
+            throttle,
+            timeouts,
+            capture,
+            secret,
+            timing,
         } => {
-            if websocket {
-                server::run(&gateway, &public, async |mut listener| {
-                    (websocket::accept(&mut listener).await, listener)
-                })
-                .await?;
-            } else {
-                server::run(&gateway, &public, async |mut listener| {
-                    (tcp::accept(&mut listener).await, listener)
-                })
-                .await?;
+            let timing = load_timing(timing)?;
+            let capabilities = capabilities(multiplex, &compression);
+            let filter = Filter::from(filter);
+            let gateway_filter = Filter::new(gateway_allow, gateway_deny);
+            spawn_admin(admin, &filter);
+            check_unique(&public)?;
+            check_unique(&udp)?;
+            check_unique(&forward)?;
+            let services = server::Services {
+                tcp: public,
+                udp,
+                forward,
+                filter,
+                capacity,
+                throttle,
+                timeouts: timeouts.into(),
+                capture: Capture::new(capture)?,
+                queue_timeout,
+            };
+            let secret = load_secret(secret)?;
+            let tls_acceptor = match (tls_cert, tls_key) {
+                (Some(cert), Some(key)) => {
+                    Some(TlsAcceptor::from(tls::server_config(&cert, &key)?))
+                }
+                _ => None,
+            };
+            match (websocket, tls_acceptor) {
+                (true, Some(acceptor)) => {
+                    let start = move |stream| {
+                        let acceptor = acceptor.clone();
+                        async move { websocket::accept_tls(stream, &acceptor).await }
+                    };
+                    server::run(
+                        server::Gateways {
+                            addr: gateway,
+                            filter: gateway_filter,
+                            start,
+                        },
+                        &services,
+                        capabilities,
+                        secret,
+                        balance,
+                        route_by_host,
+                        timing,
+                    )
+                    .await?;
+                }
+                (true, None) => {
+                    server::run(
+                        server::Gateways {
+                            addr: gateway,
+                            filter: gateway_filter,
+                            start: websocket::accept,
+                        },
+                        &services,
+                        capabilities,
+                        secret,
+                        balance,
+                        route_by_host,
+                        timing,
+                    )
+                    .await?;
+                }
+                (false, Some(acceptor)) => {
+                    let start = move |stream| {
+                        let acceptor = acceptor.clone();
+                        async move { tls::accept(stream, &acceptor).await }
+                    };
+                    server::run(
+                        server::Gateways {
+                            addr: gateway,
+                            filter: gateway_filter,
+                            start,
+                        },
+                        &services,
+                        capabilities,
+                        secret,
+                        balance,
+                        route_by_host,
+                        timing,
+                    )
+                    .await?;
+                }
+                (false, None) => {
+                    server::run(
+                        server::Gateways {
+                            addr: gateway,
+                            filter: gateway_filter,
+                            start: future::ok,
+                        },
+                        &services,
+                        capabilities,
+                        secret,
+                        balance,
+                        route_by_host,
+                        timing,
+                    )
+                    .await?;
+                }
             }
         }
         opt::Mode::Client {
             gateway,
             private,
+            udp,
+            local,
             websocket,
-        } => match websocket {
-            opt::WebSocketEnabled::Insecure | opt::WebSocketEnabled::Secure => {
-                let scheme = if websocket == opt::WebSocketEnabled::Insecure {
-                    "ws"
-                } else {
-                    "wss"
-                };
-                let uri = http::uri::Builder::new()
-                    .scheme(scheme)
-                    .authority(gateway.orig())
-                    .path_and_query("/ws/")
-                    .build()
-                    .unwrap();
-                client::run(|| websocket::connect(&uri), &private).await;
-            }
-            opt::WebSocketEnabled::Off => {
-                client::run(|| tcp::connect(&gateway), &private).await;
+            tls,
+            tls_ca,
+            tls_fingerprint,
+            multiplex,
+            compression,
+            pool_size,
+            proxy_protocol,
+            health_check,
+            health_path,
+            health_interval,
+            client_id,
+            priority,
+            hostname,
+            gateway_mode,
+            proxy,
+            admin,
+            filter,
+            throttle,
+            timeouts,
+            capture,
+            secret,
+            timing,
+        } => {
+            let timing = load_timing(timing)?;
+            let capabilities = capabilities(multiplex, &compression);
+            let filter = Filter::from(filter);
+            spawn_admin(admin, &filter);
+            check_unique(&private)?;
+            check_unique(&udp)?;
+            check_unique(&local)?;
+            let health = match health_check {
+                Some(probe) => health::spawn(
+                    probe,
+                    health_path,
+                    proxy_protocol,
+                    private.clone(),
+                    health_interval,
+                ),
+                None => health::Health::always(),
+            };
+            let timeouts = timeouts.into();
+            let capture = Capture::new(capture)?;
+            let locals = forward::Locals {
+                throttle: throttle.clone(),
+                timeouts,
+                capture: capture.clone(),
+            };
+            let private = Arc::new(client::Private {
+                tcp: private,
+                udp,
+                proxy_protocol,
+                limits: Limits::new(&throttle),
+                timeouts,
+                capture,
+                health,
+            });
+            let secret = load_secret(secret)?;
+            let identity = identity::Identity {
+                id: match client_id {
+                    Some(id) => id,
+                    None => random_client_id()?,
+                },
+                priority,
+                hostnames: hostname,
+            };
+            log::info!("Identifying as {}", identity);
+            let default_transport = match (websocket, tls) {
+                (opt::WebSocketEnabled::Insecure, _) => gateway::Transport::Ws,
+                (opt::WebSocketEnabled::Secure, _) => gateway::Transport::Wss,
+                (opt::WebSocketEnabled::Off, true) => gateway::Transport::Tls,
+                (opt::WebSocketEnabled::Off, false) => gateway::Transport::Tcp,
+            };
+            let gateways = gateway
+                .into_iter()
+                .map(|gateway| {
+                    let transport = gateway.transport.unwrap_or(default_transport);
+                    (gateway, transport)
+                })
+                .collect::<Vec<_>>();
+            let tls_config = if gateways.iter().any(|(_, transport)| transport.is_secure()) {
+                Some(tls::client_config(tls_ca.as_deref(), tls_fingerprint)?)
+            } else if tls_ca.is_some() || tls_fingerprint.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "TLS verification options require a gateway using TLS",
+                ));
+            } else {
+                None
+            };
+            let tls_config = tls_config.as_ref();
+            let remote = async {
+                match gateway_mode {
+                    gateway::Mode::All => {
+                        let clients = gateways.iter().map(|(gateway, transport)| {
+                            Box::pin(client::run(
+                                || {
+                                    gateway::connect(
+                                        gateway,
+                                        *transport,
+                                        tls_config,
+                                        proxy.as_ref(),
+                                    )
+                                },
+                                &private,
+                                capabilities,
+                                secret.clone(),
+                                pool_size,
+                                &identity,
+                                &timing,
+                            ))
+                        });
+                        let (never, _, _) = select_all(clients).await;
+                        never
+                    }
+                    gateway::Mode::Failover => {
+                        client::run(
+                            || gateway::connect_first(&gateways, tls_config, proxy.as_ref()),
+                            &private,
+                            capabilities,
+                            secret.clone(),
+                            pool_size,
+                            &identity,
+                            &timing,
+                        )
+                        .await
+                    }
+                }
+            };
+            let local = async {
+                if local.is_empty() {
+                    return pending().await;
+                }
+                forward::run(
+                    || gateway::connect_first(&gateways, tls_config, proxy.as_ref()),
+                    &local,
+                    &filter,
+                    &locals,
+                    secret.as_ref(),
+                    &identity,
+                    &timing,
+                )
+                .await
+            };
+            match select(pin!(remote), pin!(local)).await {
+                Either::Left((never, _)) => never,
+                Either::Right((result, _)) => result?,
             }
-        },
+        }
     }
 
     Ok(())
 }
+
+/// Optional features to announce in the early handshake.
+fn capabilities(multiplex: bool, compression: &[Compression]) -> Capabilities {
+    Capabilities::NONE.with(Capabilities::MULTIPLEX, multiplex)
+        | Compression::capabilities(compression)
+}
+
+fn spawn_admin(addr: Option<ListenAddr>, filter: &Filter) {
+    if let Some(addr) = addr {
+        log::info!("Serving admin endpoint: {}", addr);
+        let filter = filter.clone();
+        tokio::spawn(async move {
+            if let Err(e) = http::run_simple_server(
+                addr,
+                filter,
+                Capacity::default(),
+                (),
+                metrics::respond_to_request,
+            )
+            .await
+            {
+                log::error!("Admin endpoint failed: {}", e);
+            }
+        });
+    }
+}
+
+fn check_unique<A>(services: &[service::Service<A>]) -> Result<(), io::Error> {
+    match service::first_duplicate(services) {
+        Some(name) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Duplicate service name: {}", name),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn load_secret(options: opt::SecretOptions) -> Result<Option<Secret>, io::Error> {
+    let opt::SecretOptions {
+        secret,
+        secret_file,
+    } = options;
+
+    let secret = match (secret, secret_file) {
+        (Some(secret), _) => secret.into_bytes(),
+        (None, Some(path)) => {
+            let mut secret = fs::read(path)?;
+            // allow a trailing newline, as most editors will add one
+            while secret.last().is_some_and(u8::is_ascii_whitespace) {
+                secret.pop();
+            }
+            secret
+        }
+        (None, None) => return Ok(None),
+    };
+
+    if secret.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Shared secret is empty",
+        ));
+    }
+
+    Ok(Some(Secret::new(&secret)))
+}
+
+fn load_timing(options: opt::TimingOptions) -> Result<config::Timing, io::Error> {
+    let opt::TimingOptions {
+        handshake_timeout,
+        heartbeat_timeout,
+        backoff_min,
+        backoff_max,
+        backoff_jitter,
+        udp_session_timeout,
+    } = options;
+
+    if heartbeat_timeout > config::MAX_HEARTBEAT_TIMEOUT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Heartbeat timeout is longer than the maximum of {:?}",
+                config::MAX_HEARTBEAT_TIMEOUT
+            ),
+        ));
+    }
+    if backoff_min > backoff_max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Minimum backoff is longer than maximum backoff",
+        ));
+    }
+
+    Ok(config::Timing {
+        handshake: handshake_timeout,
+        heartbeat: heartbeat_timeout,
+        backoff: backoff::Schedule {
+            min: backoff_min,
+            max: backoff_max,
+            jitter: backoff_jitter,
+        },
+        udp_session: udp_session_timeout,
+    })
+}
+
+fn random_client_id() -> Result<String, io::Error> {
+    let mut id = [0; 4];
+    SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|ring::error::Unspecified| io::Error::other("failed to generate client id"))?;
+    Ok(id.iter().map(|b| format!("{:02x}", b)).collect())
+}
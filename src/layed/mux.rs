@@ -0,0 +1,683 @@
+use crate::layed::version::GOAWAY_VERSION;
+use bytes::{Bytes, BytesMut};
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
+use tokio::io::{ReadHalf, WriteHalf, split};
+use tokio::sync::mpsc;
+use tokio::time::{interval, timeout};
+
+// frame layout: kind (1 byte), stream id (4 bytes), length or value (4 bytes), payload
+const HEADER_LEN: usize = 9;
+
+const DATA: u8 = 0;
+const WINDOW: u8 = 1;
+const OPEN: u8 = 2;
+const FIN: u8 = 3;
+const RST: u8 = 4;
+const PING: u8 = 5;
+const GOAWAY: u8 = 6;
+
+const MAX_FRAME_LEN: usize = 16 * 1024;
+const INITIAL_WINDOW: u32 = 256 * 1024;
+
+/// Which end of the gateway connection this is.
+/// Determines the parity of locally-opened stream ids, so both sides can open streams without colliding.
+#[derive(Copy, Clone, Debug)]
+pub enum Role {
+    Server,
+    Client,
+}
+
+enum Frame {
+    Data(u32, Bytes),
+    Window(u32, u32),
+    Open(u32),
+    Fin(u32),
+    Rst(u32),
+    Ping,
+    GoAway,
+}
+
+impl Frame {
+    fn header(&self) -> [u8; HEADER_LEN] {
+        let (kind, id, value) = match *self {
+            Frame::Data(id, ref data) => (DATA, id, data.len() as u32),
+            Frame::Window(id, credit) => (WINDOW, id, credit),
+            Frame::Open(id) => (OPEN, id, 0),
+            Frame::Fin(id) => (FIN, id, 0),
+            Frame::Rst(id) => (RST, id, 0),
+            Frame::Ping => (PING, 0, 0),
+            Frame::GoAway => (GOAWAY, 0, 0),
+        };
+        let mut header = [0; HEADER_LEN];
+        header[0] = kind;
+        header[1..5].copy_from_slice(&id.to_be_bytes());
+        header[5..9].copy_from_slice(&value.to_be_bytes());
+        header
+    }
+}
+
+struct Shared {
+    streams: Mutex<Streams>,
+    closed: AtomicBool,
+    /// Whether the peer has stopped accepting new streams
+    going_away: AtomicBool,
+}
+
+struct Streams {
+    next_id: u32,
+    entries: HashMap<u32, Entry>,
+}
+
+struct Entry {
+    inbound: Option<mpsc::UnboundedSender<Result<Bytes, io::ErrorKind>>>,
+    recv_window: u32,
+    send: Arc<Mutex<SendState>>,
+}
+
+struct SendState {
+    credit: u32,
+    reset: bool,
+    waker: Option<Waker>,
+}
+
+impl Shared {
+    fn close(&self) {
+        let mut streams = self.streams.lock().unwrap();
+        self.closed.store(true, SeqCst);
+        for entry in streams.entries.values_mut() {
+            entry.reset();
+        }
+    }
+}
+
+impl Entry {
+    fn reset(&mut self) {
+        if let Some(inbound) = self.inbound.take() {
+            let _ = inbound.send(Err(io::ErrorKind::ConnectionReset));
+        }
+        let mut send = self.send.lock().unwrap();
+        send.reset = true;
+        if let Some(waker) = send.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Handle for opening new streams on a session.
+#[derive(Clone)]
+pub struct Control {
+    shared: Arc<Shared>,
+    frames: mpsc::UnboundedSender<Frame>,
+}
+
+/// Receives streams opened by the remote end of a session.
+///
+/// Dropping it tells the peer to stop opening new streams, if the peer understands that.
+pub struct Incoming {
+    streams: mpsc::UnboundedReceiver<Stream>,
+    go_away: bool,
+    control: Control,
+}
+
+/// Starts multiplexing streams over a gateway connection.
+///
+/// The session stays alive as long as the connection does, and any handle to it is held.
+/// Liveness is checked with pings, using the same agreed `heartbeat` timeout as the idle phase.
+pub fn start<T>(conn: T, role: Role, version: u8, heartbeat: Duration) -> (Control, Incoming)
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = split(conn);
+    let (frames_tx, frames_rx) = mpsc::unbounded_channel();
+    let (resets_tx, resets_rx) = mpsc::unbounded_channel();
+    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+
+    let shared = Arc::new(Shared {
+        streams: Mutex::new(Streams {
+            next_id: match role {
+                Role::Server => 2,
+                Role::Client => 1,
+            },
+            entries: HashMap::new(),
+        }),
+        closed: AtomicBool::new(false),
+        going_away: AtomicBool::new(false),
+    });
+
+    tokio::spawn({
+        let shared = Arc::clone(&shared);
+        let frames = frames_tx.downgrade();
+        async move {
+            let result = read_frames(
+                reader,
+                &shared,
+                role,
+                heartbeat,
+                incoming_tx,
+                frames,
+                resets_tx,
+            )
+            .await;
+            match result {
+                Ok(()) => log::info!("Multiplexed session closed"),
+                Err(e) => log::info!("Multiplexed session failed: {}", e),
+            }
+            shared.close();
+        }
+    });
+
+    tokio::spawn({
+        let shared = Arc::clone(&shared);
+        async move {
+            if let Err(e) = write_frames(writer, &shared, heartbeat, frames_rx, resets_rx).await {
+                log::info!("Multiplexed session failed to write: {}", e);
+            }
+            shared.close();
+        }
+    });
+
+    let control = Control {
+        shared,
+        frames: frames_tx,
+    };
+    let incoming = Incoming {
+        streams: incoming_rx,
+        go_away: version >= GOAWAY_VERSION,
+        control: control.clone(),
+    };
+    (control, incoming)
+}
+
+impl Control {
+    /// Whether new streams can no longer be opened, because the connection closed or the peer is going away.
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(SeqCst) || self.shared.going_away.load(SeqCst)
+    }
+
+    pub fn open(&self) -> Result<Stream, io::Error> {
+        let mut streams = self.shared.streams.lock().unwrap();
+        if self.shared.closed.load(SeqCst) {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        if self.shared.going_away.load(SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "peer isn't accepting new streams",
+            ));
+        }
+        let mut id = streams.next_id;
+        // after wrapping around, skip ids which long-lived streams are still using
+        while streams.entries.contains_key(&id) {
+            id = id.wrapping_add(2);
+        }
+        streams.next_id = id.wrapping_add(2);
+        // registered while holding the lock, so the reader can't see the peer's reply before the entry exists
+        self.frames
+            .send(Frame::Open(id))
+            .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?;
+        Ok(Stream::register(
+            &mut streams.entries,
+            id,
+            Arc::clone(&self.shared),
+            self.frames.clone(),
+        ))
+    }
+}
+
+impl Incoming {
+    pub async fn accept(&mut self) -> Option<Stream> {
+        self.streams.recv().await
+    }
+}
+
+impl Drop for Incoming {
+    fn drop(&mut self) {
+        if self.go_away {
+            let _ = self.control.frames.send(Frame::GoAway);
+        }
+    }
+}
+
+async fn read_frames<T: AsyncRead>(
+    mut reader: ReadHalf<T>,
+    shared: &Arc<Shared>,
+    role: Role,
+    heartbeat: Duration,
+    incoming: mpsc::UnboundedSender<Stream>,
+    frames: mpsc::WeakUnboundedSender<Frame>,
+    resets: mpsc::UnboundedSender<u32>,
+) -> Result<(), io::Error> {
+    loop {
+        let mut header = [0; HEADER_LEN];
+        match timeout(heartbeat, reader.read_exact(&mut header)).await? {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let kind = header[0];
+        let id = u32::from_be_bytes(header[1..5].try_into().unwrap());
+        let value = u32::from_be_bytes(header[5..9].try_into().unwrap());
+
+        match kind {
+            DATA => {
+                let len = value as usize;
+                if len > MAX_FRAME_LEN {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "frame too large",
+                    ));
+                }
+                let mut data = BytesMut::zeroed(len);
+                reader.read_exact(&mut data).await?;
+
+                let mut streams = shared.streams.lock().unwrap();
+                // data for a stream we've already dropped is discarded
+                if let Some(entry) = streams.entries.get_mut(&id) {
+                    entry.recv_window = match entry.recv_window.checked_sub(value) {
+                        Some(window) => window,
+                        None => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "flow control window exceeded",
+                            ));
+                        }
+                    };
+                    if let Some(inbound) = &entry.inbound {
+                        let _ = inbound.send(Ok(data.freeze()));
+                    }
+                }
+            }
+            WINDOW => {
+                let streams = shared.streams.lock().unwrap();
+                if let Some(entry) = streams.entries.get(&id) {
+                    let mut send = entry.send.lock().unwrap();
+                    send.credit = send.credit.saturating_add(value);
+                    if let Some(waker) = send.waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+            OPEN => {
+                let remote_parity = match role {
+                    Role::Server => 1,
+                    Role::Client => 0,
+                };
+                if id % 2 != remote_parity {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "bad stream id"));
+                }
+                let mut streams = shared.streams.lock().unwrap();
+                if streams.entries.contains_key(&id) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "duplicate stream id",
+                    ));
+                }
+                // if every handle to the session is gone, it's shutting down and no new streams are accepted
+                let Some(frames) = frames.upgrade() else {
+                    drop(streams);
+                    // refuse the stream, so the peer doesn't wait on it forever;
+                    // if even that's too late, closing the connection resets it instead
+                    if resets.send(id).is_err() {
+                        return Ok(());
+                    }
+                    continue;
+                };
+                let stream = Stream::register(&mut streams.entries, id, Arc::clone(shared), frames);
+                drop(streams);
+                // if nobody is accepting, the stream is dropped and reset
+                let _ = incoming.send(stream);
+            }
+            FIN => {
+                let mut streams = shared.streams.lock().unwrap();
+                if let Some(entry) = streams.entries.get_mut(&id) {
+                    entry.inbound = None;
+                }
+            }
+            RST => {
+                let mut streams = shared.streams.lock().unwrap();
+                if let Some(entry) = streams.entries.get_mut(&id) {
+                    entry.reset();
+                }
+            }
+            PING => {}
+            GOAWAY => shared.going_away.store(true, SeqCst),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown frame")),
+        }
+    }
+}
+
+async fn write_frames<T: AsyncWrite>(
+    writer: WriteHalf<T>,
+    shared: &Shared,
+    heartbeat: Duration,
+    mut frames: mpsc::UnboundedReceiver<Frame>,
+    mut resets: mpsc::UnboundedReceiver<u32>,
+) -> Result<(), io::Error> {
+    let mut writer = BufWriter::new(writer);
+    let mut ping = interval(heartbeat / 2);
+    loop {
+        let frame = tokio::select! {
+            frame = frames.recv() => match frame {
+                Some(frame) => frame,
+                None => break,
+            },
+            Some(id) = resets.recv() => Frame::Rst(id),
+            _ = ping.tick() => Frame::Ping,
+        };
+        if shared.closed.load(SeqCst) {
+            break;
+        }
+
+        write_frame(&mut writer, frame).await?;
+        while let Ok(frame) = frames.try_recv() {
+            write_frame(&mut writer, frame).await?;
+        }
+        writer.flush().await?;
+    }
+    // streams refused while the last handles were being dropped
+    while let Ok(id) = resets.try_recv() {
+        write_frame(&mut writer, Frame::Rst(id)).await?;
+    }
+    writer.shutdown().await?;
+    Ok(())
+}
+
+async fn write_frame(mut writer: impl AsyncWrite + Unpin, frame: Frame) -> Result<(), io::Error> {
+    writer.write_all(&frame.header()).await?;
+    if let Frame::Data(_, data) = frame {
+        writer.write_all(&data).await?;
+    }
+    Ok(())
+}
+
+/// A single bidirectional stream within a session.
+pub struct Stream {
+    id: u32,
+    shared: Arc<Shared>,
+    frames: mpsc::UnboundedSender<Frame>,
+    inbound: mpsc::UnboundedReceiver<Result<Bytes, io::ErrorKind>>,
+    buffered: Bytes,
+    unacked: u32,
+    send: Arc<Mutex<SendState>>,
+    fin_sent: bool,
+    fin_received: bool,
+}
+
+impl Stream {
+    fn register(
+        entries: &mut HashMap<u32, Entry>,
+        id: u32,
+        shared: Arc<Shared>,
+        frames: mpsc::UnboundedSender<Frame>,
+    ) -> Self {
+        let (inbound_tx, inbound_rx) = mpsc::unbounded_channel();
+        let send = Arc::new(Mutex::new(SendState {
+            credit: INITIAL_WINDOW,
+            reset: false,
+            waker: None,
+        }));
+        entries.insert(
+            id,
+            Entry {
+                inbound: Some(inbound_tx),
+                recv_window: INITIAL_WINDOW,
+                send: Arc::clone(&send),
+            },
+        );
+        Stream {
+            id,
+            shared,
+            frames,
+            inbound: inbound_rx,
+            buffered: Bytes::new(),
+            unacked: 0,
+            send,
+            fin_sent: false,
+            fin_received: false,
+        }
+    }
+
+    fn consumed(&mut self, n: usize) {
+        self.unacked += n as u32;
+        // batch window updates, so small reads don't each generate a frame
+        if self.unacked >= INITIAL_WINDOW / 2 {
+            let mut streams = self.shared.streams.lock().unwrap();
+            if let Some(entry) = streams.entries.get_mut(&self.id) {
+                entry.recv_window += self.unacked;
+            }
+            let _ = self.frames.send(Frame::Window(self.id, self.unacked));
+            self.unacked = 0;
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.buffered.is_empty() {
+                let n = cmp::min(buf.remaining(), this.buffered.len());
+                buf.put_slice(&this.buffered.split_to(n));
+                this.consumed(n);
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(this.inbound.poll_recv(cx)) {
+                Some(Ok(data)) => this.buffered = data,
+                Some(Err(kind)) => return Poll::Ready(Err(kind.into())),
+                None => {
+                    this.fin_received = true;
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        if this.fin_sent {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = {
+            let mut send = this.send.lock().unwrap();
+            if send.reset {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+            if send.credit == 0 {
+                send.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            let n = cmp::min(cmp::min(buf.len(), send.credit as usize), MAX_FRAME_LEN);
+            send.credit -= n as u32;
+            n
+        };
+
+        match this
+            .frames
+            .send(Frame::Data(this.id, Bytes::copy_from_slice(&buf[..n])))
+        {
+            Ok(()) => Poll::Ready(Ok(n)),
+            Err(mpsc::error::SendError(_)) => {
+                Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        // frames are flushed by the session as soon as they're queued
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.get_mut();
+        if !this.fin_sent {
+            this.fin_sent = true;
+            let _ = this.frames.send(Frame::Fin(this.id));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.shared.streams.lock().unwrap().entries.remove(&self.id);
+        if !(self.fin_sent && self.fin_received) {
+            let _ = self.frames.send(Frame::Rst(self.id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layed::config::LEGACY_HEARTBEAT_TIMEOUT;
+    use crate::layed::version::PROTOCOL_VERSION;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn roundtrip_with_half_close() {
+        let (server, client) = duplex(1024);
+        let (control, _) = start(
+            server,
+            Role::Server,
+            PROTOCOL_VERSION,
+            LEGACY_HEARTBEAT_TIMEOUT,
+        );
+        let (_, mut incoming) = start(
+            client,
+            Role::Client,
+            PROTOCOL_VERSION,
+            LEGACY_HEARTBEAT_TIMEOUT,
+        );
+
+        let mut opened = control.open().unwrap();
+        opened.write_all(b"hello").await.unwrap();
+        opened.shutdown().await.unwrap();
+
+        let mut accepted = incoming.accept().await.unwrap();
+        let mut buf = Vec::new();
+        accepted.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hello");
+
+        accepted.write_all(b"world").await.unwrap();
+        accepted.shutdown().await.unwrap();
+        let mut buf = Vec::new();
+        opened.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"world");
+    }
+
+    #[tokio::test]
+    async fn transfer_larger_than_window() {
+        let (server, client) = duplex(64 * 1024);
+        let (control, _) = start(
+            server,
+            Role::Server,
+            PROTOCOL_VERSION,
+            LEGACY_HEARTBEAT_TIMEOUT,
+        );
+        let (_, mut incoming) = start(
+            client,
+            Role::Client,
+            PROTOCOL_VERSION,
+            LEGACY_HEARTBEAT_TIMEOUT,
+        );
+
+        let data = (0..4 * INITIAL_WINDOW).map(|i| i as u8).collect::<Vec<_>>();
+        let mut opened = control.open().unwrap();
+        let send = {
+            let data = data.clone();
+            tokio::spawn(async move {
+                opened.write_all(&data).await.unwrap();
+                opened.shutdown().await.unwrap();
+                opened
+            })
+        };
+
+        let mut accepted = incoming.accept().await.unwrap();
+        let mut buf = Vec::new();
+        accepted.read_to_end(&mut buf).await.unwrap();
+        assert!(buf == data);
+        send.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn skips_ids_still_in_use() {
+        let (server, _client) = duplex(1024);
+        let (control, _) = start(
+            server,
+            Role::Server,
+            PROTOCOL_VERSION,
+            LEGACY_HEARTBEAT_TIMEOUT,
+        );
+
+        let first = control.open().unwrap();
+        // as if the counter wrapped around while the first stream was still open
+        control.shared.streams.lock().unwrap().next_id = first.id;
+        let second = control.open().unwrap();
+        assert_eq!(second.id, first.id + 2);
+    }
+
+    #[tokio::test]
+    async fn streams_reset_when_connection_drops() {
+        let (server, client) = duplex(1024);
+        let (control, _) = start(
+            server,
+            Role::Server,
+            PROTOCOL_VERSION,
+            LEGACY_HEARTBEAT_TIMEOUT,
+        );
+        let mut opened = control.open().unwrap();
+        drop(client);
+
+        let mut buf = [0; 1];
+        assert!(opened.read(&mut buf).await.is_err());
+        assert!(control.is_closed());
+    }
+
+    #[tokio::test]
+    async fn stops_opening_once_peer_goes_away() {
+        let (server, client) = duplex(1024);
+        let (control, _) = start(
+            server,
+            Role::Server,
+            PROTOCOL_VERSION,
+            LEGACY_HEARTBEAT_TIMEOUT,
+        );
+        // the client's control stands in for its active streams, which keep the session open
+        let (_client, incoming) = start(
+            client,
+            Role::Client,
+            PROTOCOL_VERSION,
+            LEGACY_HEARTBEAT_TIMEOUT,
+        );
+        assert!(!control.is_closed());
+
+        drop(incoming);
+        while !control.is_closed() {
+            tokio::task::yield_now().await;
+        }
+        let refused = control.open().err().unwrap();
+        assert_eq!(refused.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
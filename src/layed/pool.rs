@@ -0,0 +1,383 @@
+use crate::filter::Filter;
+use crate::layed::auth::Secret;
+use crate::layed::backoff::Backoff;
+use crate::layed::balance::{self, Candidate, Policy};
+use crate::layed::config::Timing;
+use crate::layed::forward::{self, Forwards, Request};
+use crate::layed::heartbeat;
+use crate::layed::identity::{self, Identity};
+use crate::layed::magic;
+use crate::layed::metrics::{METRICS, Track};
+use crate::layed::mux;
+use crate::layed::version::{FORWARD_VERSION, Hello, IDENTITY_VERSION, Negotiated};
+use crate::shutdown;
+use crate::tcp;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::pin::pin;
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Notify, oneshot};
+use tokio::time::sleep;
+
+/// Gateway connections which have completed the early handshake, grouped by the client that made them.
+///
+/// Idle gateways are kept alive with heartbeats until they're needed,
+/// and each client's gateways are handed out in the order they became idle.
+pub struct Pool<Conn> {
+    shared: Arc<Shared<Conn>>,
+    policy: Policy,
+    last: Option<String>,
+}
+
+struct Shared<Conn> {
+    clients: Mutex<HashMap<String, ClientState<Conn>>>,
+    changed: Notify,
+}
+
+struct ClientState<Conn> {
+    identity: Identity,
+    idle: VecDeque<Idle<Conn>>,
+    session: Option<(mux::Control, Negotiated)>,
+    active: Arc<AtomicUsize>,
+}
+
+struct Idle<Conn> {
+    claim: oneshot::Sender<oneshot::Sender<(Conn, Negotiated)>>,
+    version: u8,
+}
+
+/// The client chosen to relay a public connection.
+pub struct Chosen<Conn> {
+    pub identity: Identity,
+    /// Number of connections being relayed by this client
+    pub active: Arc<AtomicUsize>,
+    pub route: Route<Conn>,
+}
+
+pub enum Route<Conn> {
+    /// The client's existing multiplexed session
+    Session(mux::Control, Negotiated),
+    /// A fresh gateway, which still needs the late handshake
+    Gateway(Conn, Negotiated),
+}
+
+/// How gateways are admitted into the pool, once they're connected.
+pub struct Admission {
+    pub secret: Option<Secret>,
+    pub hello: Hello,
+    /// Whether legacy clients, which can't negotiate a protocol version, are accepted
+    pub allow_legacy: bool,
+    /// Where gateways which request forwarding are relayed to, instead of joining the pool
+    pub forwards: Arc<Forwards>,
+    pub timing: Timing,
+}
+
+/// Spawns a task to accept gateway connections into the pool.
+///
+/// Each connection's transport is started by `start_gateway_conn` in its own task,
+/// so slow or silent peers don't hold up accepting other gateways.
+pub fn spawn<Fut, Conn>(
+    mut gateway_connections: TcpListener,
+    filter: Filter,
+    start_gateway_conn: impl Fn(TcpStream) -> Fut + Send + 'static,
+    admission: Admission,
+    policy: Policy,
+) -> Pool<Conn>
+where
+    Fut: Future<Output = Result<Conn, io::Error>> + Send + 'static,
+    Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let shared = Arc::new(Shared {
+        clients: Mutex::new(HashMap::new()),
+        changed: Notify::new(),
+    });
+    let admission = Arc::new(admission);
+
+    tokio::spawn({
+        let shared = Arc::clone(&shared);
+        async move {
+            loop {
+                let mut backoff = Backoff::new(admission.timing.backoff);
+                let stream = loop {
+                    let result = tokio::select! {
+                        accepted = tcp::accept(&mut gateway_connections, &filter) => accepted,
+                        // stop accepting gateways, while existing ones are closed or drained
+                        () = shutdown::requested() => return,
+                    };
+                    match result {
+                        Ok(stream) => break stream,
+                        Err(e) => {
+                            log::error!("Error accepting gateway connections: {}", e);
+                            let delay = backoff.next();
+                            log::warn!("Retrying in {:?}", delay);
+                            sleep(delay).await;
+                            continue;
+                        }
+                    }
+                };
+
+                let started = start_gateway_conn(stream);
+                let admission = Arc::clone(&admission);
+                let shared = Arc::clone(&shared);
+                tokio::spawn(async move {
+                    match started.await {
+                        Ok(gateway) => keep_idle(gateway, &admission, shared).await,
+                        Err(e) => log::info!("Gateway handshake failed: {}", e),
+                    }
+                });
+            }
+        }
+    });
+
+    Pool {
+        shared,
+        policy,
+        last: None,
+    }
+}
+
+async fn keep_idle<Conn>(mut gateway: Conn, admission: &Admission, shared: Arc<Shared<Conn>>)
+where
+    Conn: AsyncRead + AsyncWrite + Unpin,
+{
+    let Admission {
+        secret,
+        hello,
+        allow_legacy,
+        forwards,
+        timing,
+    } = admission;
+
+    // early handshake: immediately kill unknown or incompatible connections
+    let negotiated = match magic::accept_hello(&mut gateway, secret.as_ref(), *hello, timing).await
+    {
+        Ok(negotiated) if negotiated.is_legacy() && !allow_legacy => {
+            METRICS.handshake_failures.fetch_add(1, Relaxed);
+            log::warn!(
+                "Early handshake failed: legacy client can't choose between multiple services (upgrade the client)"
+            );
+            return;
+        }
+        Ok(negotiated) => negotiated,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+            METRICS.handshake_failures.fetch_add(1, Relaxed);
+            log::warn!("Early handshake failed: incompatible client: {}", e);
+            return;
+        }
+        Err(e) => {
+            METRICS.handshake_failures.fetch_add(1, Relaxed);
+            log::info!("Early handshake failed: {}", e);
+            return;
+        }
+    };
+
+    let identity = if negotiated.version >= IDENTITY_VERSION {
+        match identity::read_from(&mut gateway, negotiated.version, timing.handshake).await {
+            Ok(identity) => identity,
+            Err(e) => {
+                METRICS.handshake_failures.fetch_add(1, Relaxed);
+                log::info!("Early handshake failed: {}", e);
+                return;
+            }
+        }
+    } else {
+        Identity::unidentified()
+    };
+
+    let request = if negotiated.version >= FORWARD_VERSION {
+        match forward::read_request(&mut gateway, timing.handshake).await {
+            Ok(request) => request,
+            Err(e) => {
+                METRICS.handshake_failures.fetch_add(1, Relaxed);
+                log::info!("Early handshake failed: {}", e);
+                return;
+            }
+        }
+    } else {
+        Request::Idle
+    };
+    if let Request::Forward(service) = request {
+        forward::serve(gateway, forwards, &service, &identity).await;
+        return;
+    }
+
+    log::info!(
+        "Early handshake succeeded for {} (protocol version {}, priority {})",
+        identity,
+        negotiated.version,
+        identity.priority
+    );
+
+    let (claim_tx, claim_rx) = oneshot::channel();
+    {
+        let mut clients = shared.clients.lock().unwrap();
+        let client = clients
+            .entry(identity.id.clone())
+            .or_insert_with(|| ClientState {
+                identity: identity.clone(),
+                idle: VecDeque::new(),
+                session: None,
+                active: Arc::new(AtomicUsize::new(0)),
+            });
+        // the client may have restarted with a different priority
+        client.identity = identity.clone();
+        client.idle.push_back(Idle {
+            claim: claim_tx,
+            version: negotiated.version,
+        });
+    }
+    shared.changed.notify_waiters();
+
+    // heartbeat: so the client can tell if the connection drops
+    let reply = {
+        let _idle = Track::new(&METRICS.idle_gateways);
+        tokio::select! {
+            reply = claim_rx => match reply {
+                Ok(reply) => reply,
+                Err(oneshot::error::RecvError { .. }) => return,
+            },
+            result = heartbeat::write_forever(&mut gateway, negotiated.heartbeat) => match result {
+                Ok(i) => match i {},
+                Err(e) => {
+                    log::info!("Heartbeat failed: {}", e);
+                    return;
+                }
+            },
+            () = shutdown::requested() => {
+                // closing the gateway tells the client to stop waiting on this server
+                log::info!("Closing idle gateway for {}, since the server is shutting down", identity);
+                return;
+            }
+        }
+    };
+
+    let _ = reply.send((gateway, negotiated));
+}
+
+impl<Conn> Pool<Conn> {
+    /// Waits for a client to be available, and chooses one according to the balancing policy.
+    ///
+    /// Clients are available if they have a live multiplexed session, or an idle gateway.
+    /// If any client serves `hostname`, only those clients are considered,
+    /// otherwise only clients which don't serve specific hostnames are.
+    /// Only gateways which negotiated at least `min_version` are used.
+    pub async fn next(&mut self, hostname: Option<&str>, min_version: u8) -> Chosen<Conn> {
+        loop {
+            let mut changed = pin!(self.shared.changed.notified());
+            // register before checking, so gateways which become idle in between aren't missed
+            changed.as_mut().enable();
+
+            let chosen = {
+                let mut clients = self.shared.clients.lock().unwrap();
+
+                for client in clients.values_mut() {
+                    client.idle.retain(|idle| !idle.claim.is_closed());
+                    if client
+                        .session
+                        .as_ref()
+                        .is_some_and(|(session, _)| session.is_closed())
+                    {
+                        client.session = None;
+                    }
+                }
+                // forget clients which have gone away entirely
+                clients.retain(|_, client| {
+                    !client.idle.is_empty()
+                        || client.session.is_some()
+                        || client.active.load(Relaxed) > 0
+                });
+
+                let serves = |client: &ClientState<Conn>| {
+                    hostname.is_some_and(|hostname| client.identity.serves(hostname))
+                };
+                let any_serves = clients.values().any(serves);
+
+                let candidates = clients
+                    .values()
+                    .filter(|client| match any_serves {
+                        true => serves(client),
+                        false => client.identity.hostnames.is_empty(),
+                    })
+                    .filter(|client| client.usable(min_version))
+                    .map(|client| Candidate {
+                        id: &client.identity.id,
+                        priority: client.identity.priority,
+                        active: client.active.load(Relaxed),
+                    })
+                    .collect::<Vec<_>>();
+
+                match balance::choose(self.policy, &candidates, self.last.as_deref()) {
+                    Some(id) => {
+                        let id = id.to_string();
+                        let client = clients.get_mut(&id).unwrap();
+                        let route = match &client.session {
+                            Some((session, negotiated)) if negotiated.version >= min_version => {
+                                Ok((session.clone(), *negotiated))
+                            }
+                            _ => {
+                                let i = client
+                                    .idle
+                                    .iter()
+                                    .position(|idle| idle.version >= min_version)
+                                    .unwrap();
+                                Err(client.idle.remove(i).unwrap())
+                            }
+                        };
+                        self.last = Some(id);
+                        Some((client.identity.clone(), Arc::clone(&client.active), route))
+                    }
+                    None => None,
+                }
+            };
+
+            match chosen {
+                Some((identity, active, Ok((session, negotiated)))) => {
+                    return Chosen {
+                        identity,
+                        active,
+                        route: Route::Session(session, negotiated),
+                    };
+                }
+                Some((identity, active, Err(Idle { claim, .. }))) => {
+                    let (reply_tx, reply_rx) = oneshot::channel();
+                    // if the heartbeat already failed, choose again
+                    if claim.send(reply_tx).is_err() {
+                        continue;
+                    }
+                    match reply_rx.await {
+                        Ok((gateway, negotiated)) => {
+                            return Chosen {
+                                identity,
+                                active,
+                                route: Route::Gateway(gateway, negotiated),
+                            };
+                        }
+                        Err(oneshot::error::RecvError { .. }) => continue,
+                    }
+                }
+                None => changed.await,
+            }
+        }
+    }
+
+    /// Records a client's multiplexed session, so it can be reused for later connections.
+    pub fn set_session(&self, identity: &Identity, session: mux::Control, negotiated: Negotiated) {
+        let mut clients = self.shared.clients.lock().unwrap();
+        if let Some(client) = clients.get_mut(&identity.id) {
+            client.session = Some((session, negotiated));
+        }
+    }
+}
+
+impl<Conn> ClientState<Conn> {
+    /// Whether the client has a session or idle gateway which negotiated at least `min_version`.
+    fn usable(&self, min_version: u8) -> bool {
+        self.session
+            .as_ref()
+            .is_some_and(|(_, negotiated)| negotiated.version >= min_version)
+            || self.idle.iter().any(|idle| idle.version >= min_version)
+    }
+}
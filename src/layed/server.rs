@@ -1,114 +1,214 @@
-use crate::config::COPY_BUFFER_SIZE;
-use crate::err::{AppliesTo, IoErrorExt};
+use crate::capacity::{Capacity, CapacityOptions, Permit};
+use crate::capture::{Capture, Direction};
+use crate::endpoint::{self, ListenAddr, Peer};
+use crate::filter::Filter;
+use crate::layed::auth::Secret;
 use crate::layed::backoff::Backoff;
-use crate::layed::config::{QUEUE_TIMEOUT, SERVER_ACCEPT_BACKOFF_SECS};
+use crate::layed::balance::Policy;
+use crate::layed::compress::{self, Compression};
+use crate::layed::config::{MAX_CONCURRENT_SNIFFS, Timing};
+use crate::layed::forward::Forwards;
+use crate::layed::header::{self, Header, Protocol};
 use crate::layed::heartbeat;
+use crate::layed::identity::Identity;
 use crate::layed::magic;
-use crate::layed::stream::spawn_idle;
-use crate::tcp;
-use futures::StreamExt;
-use futures::future::{Either, select};
-use futures::stream;
+use crate::layed::metrics::{METRICS, Track};
+use crate::layed::mux;
+use crate::layed::pool;
+use crate::layed::service::Service;
+use crate::layed::sniff;
+use crate::layed::udp;
+use crate::layed::version::{Capabilities, Hello, Negotiated};
+use crate::opt::SocketAddrsFromDns;
+use crate::relay::{self, Closed, Timeouts};
+use crate::shutdown;
+use crate::throttle::{Limits, ThrottleOptions};
+use futures::stream::{self, BoxStream};
+use futures::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::future::pending;
 use std::io;
 use std::net::SocketAddr;
-use std::pin::pin;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
 use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncWrite, copy_bidirectional_with_sizes};
-use tokio::net::TcpListener;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::time::error::Elapsed;
 use tokio::time::{sleep, timeout};
 
-static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+/// Where to receive public traffic, and where clients may forward local connections to.
+pub struct Services {
+    pub tcp: Vec<Service<ListenAddr>>,
+    pub udp: Vec<Service<SocketAddr>>,
+    pub forward: Vec<Service<SocketAddrsFromDns>>,
+    /// Which peers may send public traffic
+    pub filter: Filter,
+    /// How many connections (or UDP sessions) each public address may serve at once
+    pub capacity: CapacityOptions,
+    /// Bandwidth limits for each public TCP address
+    pub throttle: ThrottleOptions,
+    /// When to close public TCP connections
+    pub timeouts: Timeouts,
+    /// Where to capture public TCP connections' data
+    pub capture: Option<Capture>,
+    /// How long public connections may wait for a gateway
+    pub queue_timeout: Duration,
+}
+
+/// Where clients connect to the server, and how their connections are started.
+pub struct Gateways<F> {
+    pub addr: SocketAddr,
+    /// Which peers may connect as clients
+    pub filter: Filter,
+    /// Starts the transport (e.g. TLS or WebSocket) on each accepted connection
+    pub start: F,
+}
 
-pub async fn run<Fut, Conn>(
-    gateway_addr: &SocketAddr,
-    public_addr: &SocketAddr,
-    accept_gateway_conn: impl Fn(TcpListener) -> Fut + Send + 'static,
+pub async fn run<F, Fut, Conn>(
+    gateways: Gateways<F>,
+    services: &Services,
+    capabilities: Capabilities,
+    secret: Option<Secret>,
+    policy: Policy,
+    route_by_host: bool,
+    timing: Timing,
 ) -> Result<(), io::Error>
 where
-    Fut: Future<Output = (Result<Conn, io::Error>, TcpListener)> + Send,
+    F: Fn(TcpStream) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<Conn, io::Error>> + Send + 'static,
     Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
-    log::info!("Binding to gateway: {}", gateway_addr);
-    let gateway_connections = TcpListener::bind(gateway_addr).await?;
-    log::info!("Binding to public: {}", public_addr);
-    let mut public_connections = TcpListener::bind(public_addr).await?;
-
-    let mut gateway_connections = pin!(spawn_idle(|requests| {
-        stream::unfold(
-            (gateway_connections, accept_gateway_conn, requests),
-            |(mut gateway_connections, accept_gateway_conn, mut requests)| async {
-                loop {
-                    let mut backoff = Backoff::new(SERVER_ACCEPT_BACKOFF_SECS);
-                    let mut gateway = loop {
-                        let result;
-                        (result, gateway_connections) =
-                            accept_gateway_conn(gateway_connections).await;
-                        match result {
-                            Ok(gateway) => break gateway,
-                            Err(e) => {
-                                log::error!("Error accepting gateway connections: {}", e);
-                                let seconds = backoff.next();
-                                log::warn!("Retrying in {} seconds", seconds);
-                                sleep(Duration::from_secs(u64::from(seconds))).await;
-                                continue;
-                            }
-                        }
-                    };
-
-                    // early handshake: immediately kill unknown connections
-                    match magic::read_from(&mut gateway).await {
-                        Ok(()) => log::info!("Early handshake succeeded"),
-                        Err(e) => {
-                            log::info!("Early handshake failed: {}", e);
-                            continue;
-                        }
-                    }
-
-                    // heartbeat: so the client can tell if the connection drops
-                    let token = {
-                        let heartbeat = pin!(heartbeat::write_forever(&mut gateway));
-                        match select(requests.next(), heartbeat).await {
-                            Either::Left((Some(token), _)) => token,
-                            Either::Left((None, _)) => return None,
-                            Either::Right((Ok(i), _)) => match i {},
-                            Either::Right((Err(e), _)) => {
-                                log::info!("Heartbeat failed: {}", e);
-                                continue;
-                            }
-                        }
-                    };
+    log::info!("Binding to gateway: {}", gateways.addr);
+    let gateway_connections = TcpListener::bind(gateways.addr).await?;
+    let relays = Relays {
+        limits: services
+            .tcp
+            .iter()
+            .map(|service| (service.name.clone(), Limits::new(&services.throttle)))
+            .collect(),
+        timeouts: services.timeouts,
+        capture: services.capture.clone(),
+        udp_session: timing.udp_session,
+    };
+    let mut public_listeners: Vec<BoxStream<'static, (String, Result<PublicConn, io::Error>)>> =
+        Vec::new();
+    for Service { name, addr } in &services.tcp {
+        log::info!("Binding to public ({}): {}", name, addr);
+        let listener = endpoint::bind(addr).await?;
+        public_listeners.push(
+            stream::unfold(
+                (
+                    name.clone(),
+                    listener,
+                    services.filter.clone(),
+                    Capacity::new(&services.capacity),
+                ),
+                |(name, mut listener, filter, capacity)| async move {
+                    let result = capacity
+                        .accept(&mut listener, &filter)
+                        .await
+                        .map(|(stream, permit)| PublicConn::Tcp(stream, permit));
+                    Some(((name.clone(), result), (name, listener, filter, capacity)))
+                },
+            )
+            .boxed(),
+        );
+    }
+    for Service { name, addr } in &services.udp {
+        log::info!("Binding to public UDP ({}): {}", name, addr);
+        let sessions = udp::listen(
+            UdpSocket::bind(addr).await?,
+            services.filter.clone(),
+            Capacity::new(&services.capacity),
+        );
+        public_listeners.push(
+            stream::unfold(
+                (name.clone(), sessions),
+                |(name, mut sessions)| async move {
+                    let session = sessions.recv().await?;
+                    Some((
+                        (name.clone(), Ok(PublicConn::Udp(session))),
+                        (name, sessions),
+                    ))
+                },
+            )
+            .boxed(),
+        );
+    }
+    // only wait for a hostname if routing by it
+    let sniff = route_by_host.then_some(timing.handshake);
+    let mut public_connections = stream::select_all(public_listeners)
+        .map(|(name, result)| async move {
+            let result = match result {
+                Ok(conn) => Ok(Public::new(conn, sniff).await),
+                Err(e) => Err(e),
+            };
+            (name, result)
+        })
+        // sniffing may take a while, so don't let one connection hold up the others
+        .buffer_unordered(MAX_CONCURRENT_SNIFFS);
 
-                    return Some((
-                        (token, gateway),
-                        (gateway_connections, accept_gateway_conn, requests),
-                    ));
-                }
-            },
-        )
-    }));
+    let hello = Hello::new(capabilities);
+    // legacy clients don't receive a header, so they can only be used if there's no choice of service
+    let allow_legacy = services.tcp.len() == 1 && services.udp.is_empty();
+    let mut gateway_connections = pool::spawn(
+        gateway_connections,
+        gateways.filter,
+        gateways.start,
+        pool::Admission {
+            secret: secret.clone(),
+            hello,
+            allow_legacy,
+            forwards: Arc::new(Forwards::new(
+                services.forward.clone(),
+                &services.throttle,
+                services.timeouts,
+            )),
+            timing,
+        },
+        policy,
+    );
 
     'public: loop {
-        let mut backoff = Backoff::new(SERVER_ACCEPT_BACKOFF_SECS);
-        let mut public = loop {
-            match tcp::accept(&mut public_connections).await {
-                Ok(public) => break public,
-                Err(e) => {
+        let mut backoff = Backoff::new(timing.backoff);
+        let (service, public) = loop {
+            match public_connections.next().await {
+                Some((service, Ok(public))) => break (service, public),
+                Some((_, Err(e))) => {
                     log::error!("Error accepting public connections: {}", e);
-                    let seconds = backoff.next();
-                    log::warn!("Retrying in {} seconds", seconds);
-                    sleep(Duration::from_secs(u64::from(seconds))).await;
+                    let delay = backoff.next();
+                    log::warn!("Retrying in {:?}", delay);
+                    sleep(delay).await;
                     continue;
                 }
+                // no public addresses, e.g. if only forwarding local connections, so keep serving gateways
+                None => return pending().await,
+            }
+        };
+        let _queued = Track::new(&METRICS.queued_connections);
+        let header = match public.conn.addrs() {
+            Ok((source, destination)) => Header {
+                protocol: public.conn.protocol(),
+                service,
+                source,
+                destination,
+            },
+            Err(e) => {
+                log::info!("Public connection dropped: {}", e);
+                continue;
             }
         };
 
-        let mut gateway = loop {
+        loop {
             // drop public connections which wait for too long, to avoid unlimited queuing when no gateway is connected
-            let mut gateway = match timeout(QUEUE_TIMEOUT, gateway_connections.next()).await {
-                Ok(Some(gateway)) => gateway,
-                Ok(None) => return Ok(()),
+            let chosen = match timeout(
+                services.queue_timeout,
+                gateway_connections.next(public.hostname.as_deref(), header.protocol.min_version()),
+            )
+            .await
+            {
+                Ok(chosen) => chosen,
                 Err(e) => {
                     let _: Elapsed = e;
                     log::info!("Public connection expired waiting for gateway");
@@ -116,6 +216,28 @@ where
                     continue 'public;
                 }
             };
+            let pool::Chosen {
+                identity,
+                active,
+                route,
+            } = chosen;
+
+            let (mut gateway, negotiated) = match route {
+                // reuse the existing multiplexed session, if it's still alive
+                pool::Route::Session(session, negotiated) => match session.open() {
+                    Ok(stream) => {
+                        spawn_relay(
+                            public, header, stream, negotiated, identity, active, &relays,
+                        );
+                        continue 'public;
+                    }
+                    Err(e) => {
+                        log::info!("Multiplexed session with {} unusable: {}", identity, e);
+                        continue;
+                    }
+                },
+                pool::Route::Gateway(gateway, negotiated) => (gateway, negotiated),
+            };
 
             // finish heartbeat: do this as late as possible so clients can't send late handshake and disconnect
             match heartbeat::write_final(&mut gateway).await {
@@ -127,46 +249,202 @@ where
             }
 
             // late handshake: ensure that client hasn't disappeared some time after early handshake
-            match magic::read_from(&mut gateway).await {
+            match magic::read_from(&mut gateway, secret.as_ref(), timing.handshake).await {
                 Ok(()) => log::info!("Late handshake succeeded"),
                 Err(e) => {
+                    METRICS.handshake_failures.fetch_add(1, Relaxed);
                     log::info!("Late handshake failed: {}", e);
                     continue;
                 }
             }
 
-            break gateway;
-        };
+            let compression = Compression::negotiate(negotiated.capabilities);
+            if let Some(mode) = compression {
+                log::info!("Compressing gateway with {:?}", mode);
+            }
+            let gateway = compress::wrap(gateway, compression);
 
-        log::info!("Spawning ({} active)", ACTIVE.fetch_add(1, Relaxed) + 1);
-        tokio::spawn(async move {
-            let done = copy_bidirectional_with_sizes(
-                &mut public,
-                &mut gateway,
-                COPY_BUFFER_SIZE,
-                COPY_BUFFER_SIZE,
-            )
-            .await;
-            let active = ACTIVE.fetch_sub(1, Relaxed) - 1;
-            match done {
-                Ok((down, up)) => log::info!("Closing ({} active): {}/{}", active, down, up),
-                Err(e) => log::info!("Closing ({} active): {}", active, e),
+            if negotiated.capabilities.contains(Capabilities::MULTIPLEX) {
+                log::info!("Starting multiplexed session with {}", identity);
+                let (session, _) = mux::start(
+                    gateway,
+                    mux::Role::Server,
+                    negotiated.version,
+                    negotiated.heartbeat,
+                );
+                gateway_connections.set_session(&identity, session.clone(), negotiated);
+                match session.open() {
+                    Ok(stream) => spawn_relay(
+                        public, header, stream, negotiated, identity, active, &relays,
+                    ),
+                    Err(e) => log::info!("Multiplexed session failed to open stream: {}", e),
+                }
+            } else {
+                if capabilities.contains(Capabilities::MULTIPLEX) && !negotiated.is_legacy() {
+                    log::info!(
+                        "Multiplexing not enabled by {}, using a dedicated gateway",
+                        identity
+                    );
+                }
+                spawn_relay(
+                    public, header, gateway, negotiated, identity, active, &relays,
+                );
+            }
+            continue 'public;
+        }
+    }
+}
+
+/// A public connection waiting to be relayed.
+struct Public {
+    conn: PublicConn,
+    /// Hostname requested by the connection, if routing by hostname
+    hostname: Option<String>,
+    /// Data which was already read from the connection while looking for the hostname
+    prefix: Vec<u8>,
+}
+
+enum PublicConn {
+    /// Holds its listener's capacity until the relay closes
+    Tcp(endpoint::Stream, Permit),
+    Udp(udp::Session),
+}
+
+impl Public {
+    /// Reads the connection's hostname for up to `sniff`, if given.
+    async fn new(mut conn: PublicConn, sniff: Option<Duration>) -> Self {
+        let (hostname, prefix) = match (&mut conn, sniff) {
+            (PublicConn::Tcp(stream, _), Some(timeout)) => {
+                sniff::read_hostname(stream, timeout).await
             }
-        });
+            (PublicConn::Tcp(..) | PublicConn::Udp(_), _) => (None, Vec::new()),
+        };
+        Self {
+            conn,
+            hostname,
+            prefix,
+        }
     }
 }
 
-pub async fn drain_queue(listener: &mut TcpListener) {
+impl PublicConn {
+    fn protocol(&self) -> Protocol {
+        match self {
+            PublicConn::Tcp(..) => Protocol::Tcp,
+            PublicConn::Udp(_) => Protocol::Udp,
+        }
+    }
+
+    /// Peer and local addresses of the public connection.
+    fn addrs(&self) -> Result<(Peer, Peer), io::Error> {
+        match self {
+            PublicConn::Tcp(stream, _) => Ok((stream.peer_addr()?, stream.local_addr()?)),
+            PublicConn::Udp(session) => Ok((
+                Peer::Inet(session.source()),
+                Peer::Inet(session.local_addr()?),
+            )),
+        }
+    }
+}
+
+/// How to relay public connections, once they've been matched with a gateway.
+struct Relays {
+    /// Bandwidth limits for each public TCP service
+    limits: HashMap<String, Limits>,
+    timeouts: Timeouts,
+    capture: Option<Capture>,
+    /// How long UDP sessions may go without any datagrams
+    udp_session: Duration,
+}
+
+fn spawn_relay<Conn>(
+    public: Public,
+    header: Header,
+    mut gateway: Conn,
+    negotiated: Negotiated,
+    identity: Identity,
+    client_active: Arc<AtomicUsize>,
+    relays: &Relays,
+) where
+    Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = match (&public.hostname, header.protocol) {
+        (Some(hostname), _) => format!("{} ({})", header.service, hostname),
+        (None, Protocol::Udp) => format!("{} (udp)", header.service),
+        (None, Protocol::Tcp) => header.service.clone(),
+    };
+    log::info!(
+        "Spawning for {} from {} via {} ({} active, {} total)",
+        service,
+        header.source,
+        identity,
+        client_active.fetch_add(1, Relaxed) + 1,
+        METRICS.active_relays.fetch_add(1, Relaxed) + 1
+    );
+    let limits = relays
+        .limits
+        .get(&header.service)
+        .cloned()
+        .unwrap_or_default();
+    let timeouts = relays.timeouts;
+    let udp_session = relays.udp_session;
+    let tap = match (&relays.capture, header.protocol) {
+        (Some(capture), Protocol::Tcp) => capture.start(header.source, header.destination),
+        (_, Protocol::Tcp | Protocol::Udp) => None,
+    };
+    tokio::spawn(async move {
+        let _draining = shutdown::guard();
+        let Public { conn, prefix, .. } = public;
+        let done = async {
+            // legacy clients only have one private address, so they don't expect a header
+            if !negotiated.is_legacy() {
+                header::write_to(&mut gateway, &header, negotiated.version).await?;
+            }
+            match conn {
+                PublicConn::Tcp(public, _permit) => {
+                    if !prefix.is_empty() {
+                        // already read from the public connection, so it's not seen by the relay's tap
+                        if let Some(tap) = &tap {
+                            tap.record(Direction::ToServer, &prefix);
+                        }
+                        gateway.write_all(&prefix).await?;
+                    }
+                    let (mut public, mut gateway) = limits.apply(public, &mut gateway);
+                    Ok(relay::copy(&mut public, &mut gateway, timeouts, tap).await)
+                }
+                PublicConn::Udp(session) => {
+                    Ok(udp::relay_public(session, &mut gateway, udp_session)
+                        .await
+                        .into())
+                }
+            }
+        }
+        .await;
+        let closed = done.unwrap_or_else(|e| Closed::from(Err(e)));
+        client_active.fetch_sub(1, Relaxed);
+        let active = METRICS.active_relays.fetch_sub(1, Relaxed) - 1;
+        METRICS.bytes_to_private.fetch_add(closed.a_to_b, Relaxed);
+        METRICS.bytes_to_public.fetch_add(closed.b_to_a, Relaxed);
+        log::info!(
+            "Closing ({} active): {}/{}, {}",
+            active,
+            closed.a_to_b,
+            closed.b_to_a,
+            closed.reason
+        );
+    });
+}
+
+async fn drain_queue(
+    connections: &mut (impl Stream<Item = (String, Result<Public, io::Error>)> + Unpin),
+) {
     loop {
         // timeout because we need to yield to receive the second queued conn
         // (listener.poll_recv() won't return Poll::Ready twice in a row,
         //  even if there are multiple queued connections)
-        match timeout(Duration::from_millis(1), listener.accept()).await {
-            Ok(Ok((_, _))) => log::info!("Queued conn dropped"),
-            Ok(Err(e)) => match e.applies_to() {
-                AppliesTo::Connection => log::info!("Queued conn dropped: {}", e),
-                AppliesTo::Listener => break,
-            },
+        match timeout(Duration::from_millis(1), connections.next()).await {
+            Ok(Some((_, Ok(_)))) => log::info!("Queued conn dropped"),
+            Ok(Some((_, Err(_)))) | Ok(None) => break,
             Err(e) => {
                 let _: Elapsed = e;
                 break;
@@ -0,0 +1,77 @@
+use std::fmt::Display;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Name used for services given without one.
+pub const DEFAULT_NAME: &str = "default";
+
+/// An address associated with a service name, which is how the server tells the client where to relay to.
+#[derive(Clone, Debug)]
+pub struct Service<A> {
+    pub name: String,
+    pub addr: A,
+}
+
+#[derive(Debug, Error)]
+pub enum BadService<E: Display> {
+    #[error("service name is empty")]
+    EmptyName,
+    #[error("service name is longer than 255 bytes")]
+    NameTooLong,
+    #[error("{0}")]
+    InvalidAddr(E),
+}
+
+impl<A: FromStr> FromStr for Service<A>
+where
+    A::Err: Display,
+{
+    type Err = BadService<A::Err>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, addr) = match s.split_once('=') {
+            Some((name, addr)) => (name, addr),
+            None => (DEFAULT_NAME, s),
+        };
+        match () {
+            _ if name.is_empty() => return Err(BadService::EmptyName),
+            _ if name.len() > usize::from(u8::MAX) => return Err(BadService::NameTooLong),
+            _ => {}
+        }
+        Ok(Service {
+            name: name.to_string(),
+            addr: addr.parse().map_err(BadService::InvalidAddr)?,
+        })
+    }
+}
+
+pub fn find<'a, A>(services: &'a [Service<A>], name: &str) -> Option<&'a A> {
+    services.iter().find(|s| s.name == name).map(|s| &s.addr)
+}
+
+pub fn first_duplicate<A>(services: &[Service<A>]) -> Option<&str> {
+    services.iter().enumerate().find_map(|(i, s)| {
+        services[..i]
+            .iter()
+            .any(|prev| prev.name == s.name)
+            .then_some(s.name.as_str())
+    })
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    case!(bare_addr: assert!(matches!(Service::<SocketAddr>::from_str("127.0.0.1:22"), Ok(s) if s.name == DEFAULT_NAME)));
+    case!(named_addr: assert!(matches!(Service::<SocketAddr>::from_str("ssh=127.0.0.1:22"), Ok(s) if s.name == "ssh" && s.addr.port() == 22)));
+    case!(named_ipv6: assert!(matches!(Service::<SocketAddr>::from_str("web=[::1]:80"), Ok(s) if s.name == "web")));
+
+    case!(empty_name: assert!(matches!(Service::<SocketAddr>::from_str("=127.0.0.1:22"), Err(BadService::EmptyName))));
+    case!(long_name: assert!(matches!(Service::<SocketAddr>::from_str(&format!("{}=127.0.0.1:22", "a".repeat(256))), Err(BadService::NameTooLong))));
+    case!(bad_addr: assert!(matches!(Service::<SocketAddr>::from_str("ssh=localhost"), Err(BadService::InvalidAddr(_)))));
+
+    case!(no_duplicates: assert_eq!(first_duplicate(&["a=127.0.0.1:1".parse::<Service<SocketAddr>>().unwrap(), "b=127.0.0.1:2".parse().unwrap()]), None));
+    case!(duplicates: assert_eq!(first_duplicate(&["a=127.0.0.1:1".parse::<Service<SocketAddr>>().unwrap(), "a=127.0.0.1:2".parse().unwrap()]), Some("a")));
+}
@@ -0,0 +1,239 @@
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+
+/// Most bytes to read while looking for a hostname, which is enough for any reasonable TLS ClientHello.
+const MAX_SNIFF_LEN: usize = 16 * 1024 + 5;
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_EXT_SERVER_NAME: u16 = 0x0000;
+const TLS_SERVER_NAME_HOST: u8 = 0x00;
+
+#[derive(Debug, PartialEq)]
+pub enum Sniffed {
+    /// More data is needed to tell
+    Incomplete,
+    Found(String),
+    NotFound,
+}
+
+/// Reads the start of a connection, looking for the HTTP `Host` header or TLS SNI.
+///
+/// Returns the hostname, if found, along with the bytes that were read,
+/// which must be forwarded before the rest of the connection.
+/// Gives up after `handshake`, since the connection may be waiting for the server to speak first.
+pub async fn read_hostname(
+    mut stream: impl AsyncRead + Unpin,
+    handshake: Duration,
+) -> (Option<String>, Vec<u8>) {
+    let mut buf = Vec::new();
+    let sniff = async {
+        loop {
+            match hostname(&buf) {
+                Sniffed::Found(hostname) => return Some(hostname),
+                Sniffed::NotFound => return None,
+                Sniffed::Incomplete if buf.len() >= MAX_SNIFF_LEN => return None,
+                Sniffed::Incomplete => {}
+            }
+            let mut chunk = [0; 4096];
+            let max = (MAX_SNIFF_LEN - buf.len()).min(chunk.len());
+            match stream.read(&mut chunk[..max]).await {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            }
+        }
+    };
+    // the connection may never send anything, e.g. if the server is expected to speak first
+    let hostname = timeout(handshake, sniff).await.ok().flatten();
+    (hostname.map(|h| h.to_ascii_lowercase()), buf)
+}
+
+pub fn hostname(buf: &[u8]) -> Sniffed {
+    match buf.first() {
+        None => Sniffed::Incomplete,
+        Some(&TLS_HANDSHAKE) => tls_server_name(buf),
+        Some(_) => http_host(buf),
+    }
+}
+
+fn http_host(buf: &[u8]) -> Sniffed {
+    let mut lines = buf.split(|&b| b == b'\n');
+    // request line, which must be complete before any headers
+    match lines.next() {
+        Some(line)
+            if !line
+                .iter()
+                .all(|b| b.is_ascii_graphic() || b" \r".contains(b)) =>
+        {
+            return Sniffed::NotFound;
+        }
+        _ => {}
+    }
+    let mut complete_lines = buf.iter().filter(|&&b| b == b'\n').count();
+    if complete_lines == 0 {
+        return Sniffed::Incomplete;
+    }
+    complete_lines -= 1;
+    for line in lines.take(complete_lines) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            // end of headers
+            return Sniffed::NotFound;
+        }
+        let Some(colon) = line.iter().position(|&b| b == b':') else {
+            continue;
+        };
+        let (name, value) = line.split_at(colon);
+        if !name.eq_ignore_ascii_case(b"host") {
+            continue;
+        }
+        let Ok(value) = std::str::from_utf8(&value[1..]) else {
+            return Sniffed::NotFound;
+        };
+        return Sniffed::Found(strip_port(value.trim()).to_string());
+    }
+    Sniffed::Incomplete
+}
+
+fn strip_port(host: &str) -> &str {
+    if let Some(rest) = host.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match host.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => host,
+    }
+}
+
+fn tls_server_name(buf: &[u8]) -> Sniffed {
+    let Some(header) = buf.get(..5) else {
+        return Sniffed::Incomplete;
+    };
+    let record_len = usize::from(u16::from_be_bytes([header[3], header[4]]));
+    let Some(record) = buf.get(5..5 + record_len) else {
+        return Sniffed::Incomplete;
+    };
+    match client_hello_server_name(record) {
+        Some(name) => Sniffed::Found(name),
+        None => Sniffed::NotFound,
+    }
+}
+
+fn client_hello_server_name(record: &[u8]) -> Option<String> {
+    let mut r = Reader(record);
+    if r.u8()? != TLS_CLIENT_HELLO {
+        return None;
+    }
+    let hello_len = r.u24()?;
+    let mut hello = Reader(r.bytes(hello_len)?);
+    hello.bytes(2 + 32)?; // version, random
+    let session_id_len = hello.u8()?;
+    hello.bytes(usize::from(session_id_len))?;
+    let cipher_suites_len = hello.u16()?;
+    hello.bytes(usize::from(cipher_suites_len))?;
+    let compression_len = hello.u8()?;
+    hello.bytes(usize::from(compression_len))?;
+    let extensions_len = hello.u16()?;
+    let mut extensions = Reader(hello.bytes(usize::from(extensions_len))?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let len = extensions.u16()?;
+        let mut data = Reader(extensions.bytes(usize::from(len))?);
+        if kind != TLS_EXT_SERVER_NAME {
+            continue;
+        }
+        let list_len = data.u16()?;
+        let mut list = Reader(data.bytes(usize::from(list_len))?);
+        while !list.0.is_empty() {
+            let name_type = list.u8()?;
+            let name_len = list.u16()?;
+            let name = list.bytes(usize::from(name_len))?;
+            if name_type == TLS_SERVER_NAME_HOST {
+                return std::str::from_utf8(name).ok().map(str::to_string);
+            }
+        }
+    }
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.bytes(3)
+            .map(|b| usize::from(b[0]) << 16 | usize::from(b[1]) << 8 | usize::from(b[2]))
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+
+    fn found(s: &str) -> Sniffed {
+        Sniffed::Found(s.to_string())
+    }
+
+    fn client_hello(server_name: Option<&str>) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        // an unrelated extension first (supported_versions)
+        extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        if let Some(name) = server_name {
+            let name = name.as_bytes();
+            let name_len = name.len() as u16;
+            extensions.extend_from_slice(&0u16.to_be_bytes());
+            extensions.extend_from_slice(&(name_len + 5).to_be_bytes());
+            extensions.extend_from_slice(&(name_len + 3).to_be_bytes());
+            extensions.push(0);
+            extensions.extend_from_slice(&name_len.to_be_bytes());
+            extensions.extend_from_slice(name);
+        }
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0; 32]);
+        hello.push(0); // session id
+        hello.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // cipher suites
+        hello.extend_from_slice(&[0x01, 0x00]); // compression
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+        let mut handshake = vec![0x01, 0, 0, 0];
+        handshake[1..].copy_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&hello);
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    case!(empty: assert_eq!(hostname(b""), Sniffed::Incomplete));
+
+    case!(http_host: assert_eq!(hostname(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"), found("example.com")));
+    case!(http_host_port: assert_eq!(hostname(b"GET / HTTP/1.1\r\nhost: example.com:8080\r\n"), found("example.com")));
+    case!(http_host_ipv6: assert_eq!(hostname(b"GET / HTTP/1.1\r\nHost: [::1]:8080\r\n"), found("::1")));
+    case!(http_host_later: assert_eq!(hostname(b"GET / HTTP/1.1\r\nAccept: */*\r\nHost: a.example\r\n"), found("a.example")));
+    case!(http_partial_line: assert_eq!(hostname(b"GET / HTTP/1.1\r\nHost: exam"), Sniffed::Incomplete));
+    case!(http_partial_request: assert_eq!(hostname(b"GET / HT"), Sniffed::Incomplete));
+    case!(http_no_host: assert_eq!(hostname(b"GET / HTTP/1.0\r\nAccept: */*\r\n\r\n"), Sniffed::NotFound));
+    case!(binary: assert_eq!(hostname(b"\x00\x01\x02\n"), Sniffed::NotFound));
+
+    case!(tls_sni: assert_eq!(hostname(&client_hello(Some("example.com"))), found("example.com")));
+    case!(tls_no_sni: assert_eq!(hostname(&client_hello(None)), Sniffed::NotFound));
+    case!(tls_partial: assert_eq!(hostname(&client_hello(Some("example.com"))[..20]), Sniffed::Incomplete));
+}
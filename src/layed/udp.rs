@@ -0,0 +1,307 @@
+use crate::capacity::{Capacity, Permit};
+use crate::filter::Filter;
+use crate::layed::config::{MAX_UDP_SESSIONS, UDP_SESSION_QUEUE};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::{Instant, sleep_until};
+
+const MAX_DATAGRAM_LEN: usize = u16::MAX as usize;
+
+/// Datagrams from one public source address, which are relayed together over a gateway.
+pub struct Session {
+    source: SocketAddr,
+    socket: Arc<UdpSocket>,
+    datagrams: mpsc::Receiver<Vec<u8>>,
+    sessions: Sessions,
+    /// Holds its listener's capacity until the session closes
+    _permit: Permit,
+}
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>;
+
+impl Session {
+    pub fn source(&self) -> SocketAddr {
+        self.source
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.socket.local_addr()
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // so the listener starts a new session for any later datagrams from this source
+        self.datagrams.close();
+        let mut sessions = self.sessions.lock().unwrap();
+        // a new session may have already replaced this one
+        if let Entry::Occupied(entry) = sessions.entry(self.source)
+            && entry.get().is_closed()
+        {
+            entry.remove();
+        }
+    }
+}
+
+/// Spawns a task to receive datagrams on a public socket, grouping them into sessions by source address.
+/// Datagrams from sources which the filter doesn't permit are dropped,
+/// as are datagrams from new sources once there are `MAX_UDP_SESSIONS` sessions, or the capacity is reached.
+pub fn listen(
+    socket: UdpSocket,
+    filter: Filter,
+    capacity: Capacity,
+) -> mpsc::UnboundedReceiver<Session> {
+    let (new_sessions, new_sessions_rx) = mpsc::unbounded_channel();
+    let socket = Arc::new(socket);
+    let sessions = Sessions::default();
+
+    tokio::spawn(async move {
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+        while !new_sessions.is_closed() {
+            let (len, source) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    // errors are from previous sends (e.g. ICMP port unreachable), and don't affect the socket
+                    log::debug!("Error receiving public datagram: {}", e);
+                    continue;
+                }
+            };
+            if !filter.permits(source.ip()) {
+                log::debug!("Rejected datagram from {}", source);
+                continue;
+            }
+            let datagram = buf[..len].to_vec();
+
+            let (session, new_session) = {
+                let mut sessions_guard = sessions.lock().unwrap();
+                match sessions_guard.get(&source) {
+                    Some(session) if !session.is_closed() => (session.clone(), None),
+                    None if sessions_guard.len() >= MAX_UDP_SESSIONS => {
+                        log::debug!("Public datagram from {} dropped: too many sessions", source);
+                        continue;
+                    }
+                    Some(_) | None => {
+                        let permit = match capacity.try_admit(source.ip()) {
+                            Ok(permit) => permit,
+                            Err(e) => {
+                                log::debug!("Public datagram from {} dropped: {}", source, e);
+                                continue;
+                            }
+                        };
+                        let (tx, rx) = mpsc::channel(UDP_SESSION_QUEUE);
+                        sessions_guard.insert(source, tx.clone());
+                        let session = Session {
+                            source,
+                            socket: Arc::clone(&socket),
+                            datagrams: rx,
+                            sessions: Arc::clone(&sessions),
+                            _permit: permit,
+                        };
+                        (tx, Some(session))
+                    }
+                }
+            };
+            // sent after unlocking, since dropping an unwanted session locks the map again
+            if let Some(new_session) = new_session
+                && new_sessions.send(new_session).is_err()
+            {
+                break;
+            }
+            // like any other UDP hop, drop datagrams if the receiver can't keep up
+            if let Err(mpsc::error::TrySendError::Full(_)) = session.try_send(datagram) {
+                log::debug!("Public datagram from {} dropped", source);
+            }
+        }
+    });
+
+    new_sessions_rx
+}
+
+/// Relays a public session's datagrams over the gateway, until it's idle for `timeout`.
+///
+/// Returns the number of bytes relayed in each direction, not including framing.
+pub async fn relay_public(
+    mut session: Session,
+    gateway: impl AsyncRead + AsyncWrite,
+    timeout: Duration,
+) -> Result<(u64, u64), io::Error> {
+    let (source, socket, datagrams) = (session.source, &session.socket, &mut session.datagrams);
+    let (mut reader, mut writer) = tokio::io::split(gateway);
+    let activity = Activity::new();
+    let (mut up, mut down) = (0, 0);
+
+    let to_gateway = async {
+        while let Some(datagram) = datagrams.recv().await {
+            activity.touch();
+            write_frame(&mut writer, &datagram).await?;
+            up += datagram.len() as u64;
+        }
+        Ok(())
+    };
+    let from_gateway = async {
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+        while let Some(len) = read_frame(&mut reader, &mut buf).await? {
+            activity.touch();
+            socket.send_to(&buf[..len], source).await?;
+            down += len as u64;
+        }
+        Ok(())
+    };
+
+    let result = tokio::select! {
+        result = to_gateway => result,
+        result = from_gateway => result,
+        () = activity.expired(timeout) => Ok(()),
+    };
+    result.map(|()| (up, down))
+}
+
+/// Relays datagrams from the gateway to a private address, until it's idle for `timeout`.
+///
+/// Returns the number of bytes relayed in each direction, not including framing.
+pub async fn relay_private(
+    gateway: impl AsyncRead + AsyncWrite,
+    private: UdpSocket,
+    timeout: Duration,
+) -> Result<(u64, u64), io::Error> {
+    let (mut reader, mut writer) = tokio::io::split(gateway);
+    let activity = Activity::new();
+    let (mut up, mut down) = (0, 0);
+
+    let from_gateway = async {
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+        while let Some(len) = read_frame(&mut reader, &mut buf).await? {
+            activity.touch();
+            private.send(&buf[..len]).await?;
+            up += len as u64;
+        }
+        Ok(())
+    };
+    let to_gateway = async {
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+        loop {
+            let len = match private.recv(&mut buf).await {
+                Ok(len) => len,
+                Err(e) => {
+                    // errors are from previous sends (e.g. ICMP port unreachable), and don't affect the socket
+                    log::debug!("Error receiving private datagram: {}", e);
+                    continue;
+                }
+            };
+            activity.touch();
+            write_frame(&mut writer, &buf[..len]).await?;
+            down += len as u64;
+        }
+    };
+
+    let result = tokio::select! {
+        result = from_gateway => result,
+        result = to_gateway => result,
+        () = activity.expired(timeout) => Ok(()),
+    };
+    result.map(|()| (up, down))
+}
+
+/// Binds a socket to send datagrams to a private address, from an address in the same family.
+pub async fn connect(addrs: &[SocketAddr]) -> Result<UdpSocket, io::Error> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "No addresses");
+    for addr in addrs {
+        let bind_addr: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0; 16], 0).into(),
+        };
+        let result = async {
+            let socket = UdpSocket::bind(bind_addr).await?;
+            socket.connect(addr).await?;
+            Ok(socket)
+        };
+        match result.await {
+            Ok(socket) => return Ok(socket),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Datagrams are framed with a 16-bit length prefix, since the gateway is a byte stream.
+async fn write_frame(
+    mut writer: impl AsyncWrite + Unpin,
+    datagram: &[u8],
+) -> Result<(), io::Error> {
+    let len = u16::try_from(datagram.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "datagram too long"))?;
+    let mut buf = Vec::with_capacity(2 + datagram.len());
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(datagram);
+    writer.write_all(&buf).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads a datagram into `buf`, returning its length, or `None` if the gateway was closed.
+async fn read_frame(
+    mut reader: impl AsyncRead + Unpin,
+    buf: &mut [u8],
+) -> Result<Option<usize>, io::Error> {
+    let len = match reader.read_u16().await {
+        Ok(len) => usize::from(len),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    reader.read_exact(&mut buf[..len]).await?;
+    Ok(Some(len))
+}
+
+/// Tracks when a session was last used, since UDP has no explicit end.
+struct Activity {
+    last: Mutex<Instant>,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            last: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    async fn expired(&self, timeout: Duration) {
+        loop {
+            let deadline = *self.last.lock().unwrap() + timeout;
+            if deadline <= Instant::now() {
+                return;
+            }
+            sleep_until(deadline).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn frames_roundtrip() {
+        let (mut a, mut b) = duplex(1024);
+        write_frame(&mut a, b"hello").await.unwrap();
+        write_frame(&mut a, b"").await.unwrap();
+        drop(a);
+
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+        assert_eq!(read_frame(&mut b, &mut buf).await.unwrap(), Some(5));
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(read_frame(&mut b, &mut buf).await.unwrap(), Some(0));
+        assert_eq!(read_frame(&mut b, &mut buf).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::layed::config::LEGACY_HEARTBEAT_TIMEOUT;
+use std::ops::{BitAnd, BitOr};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Protocol version spoken by this build.
+pub const PROTOCOL_VERSION: u8 = 8;
+/// Oldest protocol version that this build can still speak.
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+/// Version assigned to peers which only send the bare magic byte, and can't negotiate.
+pub const LEGACY_VERSION: u8 = 0;
+/// First protocol version in which clients send an `Identity` after the early handshake.
+pub const IDENTITY_VERSION: u8 = 2;
+/// First protocol version in which the `Identity` includes hostnames to route by.
+pub const HOSTNAMES_VERSION: u8 = 3;
+/// First protocol version in which the `Header` includes the protocol to relay, allowing UDP.
+pub const UDP_VERSION: u8 = 4;
+/// First protocol version in which clients send a `Request` after the `Identity`, allowing local forwarding.
+pub const FORWARD_VERSION: u8 = 5;
+/// First protocol version in which both sides exchange heartbeat timeouts after the early handshake.
+pub const HEARTBEAT_VERSION: u8 = 6;
+/// First protocol version in which the `Header` can mark public connections from unix sockets, which have no address.
+pub const UNIX_VERSION: u8 = 7;
+/// First protocol version in which multiplexed sessions tell the peer when they stop accepting new streams.
+pub const GOAWAY_VERSION: u8 = 8;
+
+/// Set of optional features a peer has enabled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    /// Multiplex public connections over a single gateway connection
+    pub const MULTIPLEX: Self = Self(1 << 0);
+    /// Authenticate with a shared secret
+    pub const AUTH: Self = Self(1 << 1);
+    /// Compress the gateway with deflate
+    pub const DEFLATE: Self = Self(1 << 2);
+    /// Compress the gateway with zstd
+    pub const ZSTD: Self = Self(1 << 3);
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn with(self, other: Self, enabled: bool) -> Self {
+        if enabled {
+            Self(self.0 | other.0)
+        } else {
+            self
+        }
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Version and capabilities announced by one side of the early handshake.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u8,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    pub fn new(capabilities: Capabilities) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+}
+
+/// Version, capabilities and heartbeat timeout that both sides agreed on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: u8,
+    pub capabilities: Capabilities,
+    /// Until `HEARTBEAT_VERSION`, this is always the default
+    pub heartbeat: Duration,
+}
+
+impl Negotiated {
+    pub const LEGACY: Self = Self {
+        version: LEGACY_VERSION,
+        capabilities: Capabilities::NONE,
+        heartbeat: LEGACY_HEARTBEAT_TIMEOUT,
+    };
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == LEGACY_VERSION
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum Incompatible {
+    #[error(
+        "peer speaks protocol version {0}, but at least version {MIN_PROTOCOL_VERSION} is required (upgrade the peer)"
+    )]
+    PeerTooOld(u8),
+    #[error("peer has a shared secret configured, but this side doesn't")]
+    MissingSecret,
+    #[error("this side has a shared secret configured, but the peer doesn't")]
+    UnexpectedSecret,
+}
+
+/// Agrees on the highest version that both sides speak, and the capabilities that both sides enabled.
+///
+/// Each side's `version` is the newest it speaks, and it's assumed to speak everything down to `MIN_PROTOCOL_VERSION`.
+pub fn negotiate(ours: Hello, theirs: Hello) -> Result<Negotiated, Incompatible> {
+    if theirs.version < MIN_PROTOCOL_VERSION {
+        return Err(Incompatible::PeerTooOld(theirs.version));
+    }
+    // if the peer is newer, it will either downgrade to our version, or reject us as too old
+
+    match (
+        ours.capabilities.contains(Capabilities::AUTH),
+        theirs.capabilities.contains(Capabilities::AUTH),
+    ) {
+        (false, true) => return Err(Incompatible::MissingSecret),
+        (true, false) => return Err(Incompatible::UnexpectedSecret),
+        (true, true) | (false, false) => {}
+    }
+
+    Ok(Negotiated {
+        version: ours.version.min(theirs.version),
+        capabilities: ours.capabilities & theirs.capabilities,
+        heartbeat: LEGACY_HEARTBEAT_TIMEOUT,
+    })
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+
+    const MUX: Capabilities = Capabilities::MULTIPLEX;
+    const AUTH: Capabilities = Capabilities::AUTH;
+
+    fn hello(version: u8, capabilities: Capabilities) -> Hello {
+        Hello { version, capabilities }
+    }
+
+    fn negotiated(version: u8, capabilities: Capabilities) -> Negotiated {
+        Negotiated { version, capabilities, heartbeat: LEGACY_HEARTBEAT_TIMEOUT }
+    }
+
+    case!(same: assert_eq!(negotiate(hello(1, MUX), hello(1, MUX)), Ok(negotiated(1, MUX))));
+    case!(newer_peer: assert_eq!(negotiate(hello(1, MUX), hello(7, MUX)), Ok(negotiated(1, MUX))));
+    case!(older_peer: assert_eq!(negotiate(hello(2, MUX), hello(1, MUX)), Ok(negotiated(1, MUX))));
+    case!(only_ours_mux: assert_eq!(negotiate(hello(1, MUX), hello(1, Capabilities::NONE)), Ok(negotiated(1, Capabilities::NONE))));
+    case!(unknown_caps_ignored: assert_eq!(negotiate(hello(1, MUX), hello(1, Capabilities::from_bits(0x8000_0001))), Ok(negotiated(1, MUX))));
+    case!(too_old: assert_eq!(negotiate(hello(1, MUX), hello(0, MUX)), Err(Incompatible::PeerTooOld(0))));
+    case!(missing_secret: assert_eq!(negotiate(hello(1, MUX), hello(1, AUTH)), Err(Incompatible::MissingSecret)));
+    case!(unexpected_secret: assert_eq!(negotiate(hello(1, AUTH), hello(1, MUX)), Err(Incompatible::UnexpectedSecret)));
+    case!(both_secret: assert_eq!(negotiate(hello(1, AUTH), hello(1, AUTH)), Ok(negotiated(1, AUTH))));
+}
@@ -10,17 +10,31 @@ mod transmitted;
 mod vealed;
 
 mod body;
+mod capacity;
+mod capture;
 mod config;
+mod endpoint;
 mod err;
+mod filter;
 mod future;
 mod http;
 mod opt;
+mod proxy;
+mod proxy_protocol;
+mod relay;
+mod shutdown;
 mod tcp;
+mod throttle;
+mod tls;
 mod websocket;
 
 #[tokio::main]
 async fn main() -> Result<(), err::DisplayError> {
-    let opt::Options { verbose, command } = clap::Parser::parse();
+    let opt::Options {
+        verbose,
+        drain_timeout,
+        command,
+    } = clap::Parser::parse();
 
     env_logger::Builder::new()
         .filter_level(match verbose {
@@ -30,12 +44,33 @@ async fn main() -> Result<(), err::DisplayError> {
         })
         .init();
 
-    match command {
-        opt::Command::Directed(options) => directed::main(options).await?,
-        opt::Command::Flected(options) => flected::main(options).await?,
-        opt::Command::Layed(options) => layed::main(options).await?,
-        opt::Command::Transmitted(options) => transmitted::main(options).await?,
-        opt::Command::Vealed(options) => vealed::main(options).await?,
+    tokio::spawn(async {
+        if let Err(e) = shutdown::wait_for_signal().await {
+            log::error!("Failed to listen for signals: {}", e);
+        }
+    });
+
+    let run = async {
+        match command {
+            opt::Command::Directed(options) => directed::main(options).await?,
+            opt::Command::Flected(options) => flected::main(options).await?,
+            opt::Command::Layed(options) => layed::main(options).await?,
+            opt::Command::Transmitted(options) => transmitted::main(options).await?,
+            opt::Command::Vealed(options) => vealed::main(options).await?,
+        }
+        Ok::<(), err::Error>(())
+    };
+
+    // dropping the command stops its listeners, while spawned connections are left to drain
+    tokio::select! {
+        result = run => {
+            result?;
+            // some commands stop by themselves once shutdown is requested, e.g. HTTP servers
+            if shutdown::is_requested() {
+                shutdown::drain(drain_timeout).await;
+            }
+        }
+        () = shutdown::requested() => shutdown::drain(drain_timeout).await,
     }
 
     Ok(())
@@ -3,6 +3,7 @@ use std::io;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::ops::Deref;
 use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[clap(version, about)]
@@ -11,11 +12,18 @@ pub struct Options {
     #[arg(short = 'v', long = "verbose", action = ArgAction::Count, global = true)]
     pub verbose: u8,
 
+    /// How long to let active connections finish after SIGINT or SIGTERM, before exiting (e.g. `30s` or `2m`).
+    ///
+    /// New connections aren't accepted while draining. A second signal exits immediately.
+    #[arg(long, default_value = "30s", value_parser = parse_duration, global = true)]
+    pub drain_timeout: Duration,
+
     #[command(subcommand)]
     pub command: Command,
 }
 
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum Command {
     Directed(crate::directed::opt::Options),
     Flected(crate::flected::opt::Options),
@@ -66,10 +74,45 @@ impl FromStr for SocketAddrsFromDns {
     }
 }
 
+pub fn parse_duration(duration: &str) -> Result<Duration, &'static str> {
+    let (number, unit) = match duration.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => duration.split_at(i),
+        None => (duration, "s"),
+    };
+    let scale = match unit {
+        "ms" => 0.001,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 60.0 * 60.0,
+        _ => return Err("duration unit must be `ms`, `s`, `m`, or `h`"),
+    };
+    let number = number
+        .parse::<f64>()
+        .map_err(|_| "duration must be a number with an optional unit (e.g. `500ms` or `10s`)")?;
+    match Duration::try_from_secs_f64(number * scale) {
+        Ok(duration) if duration.is_zero() => Err("duration must be greater than zero"),
+        Ok(duration) => Ok(duration),
+        Err(_) => Err("duration is out of range"),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[rustfmt::skip]
+    mod duration {
+        use super::*;
+
+        case!(ms: assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250))));
+        case!(secs: assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500))));
+        case!(mins: assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120))));
+        case!(bare: assert_eq!(parse_duration("10"), Ok(Duration::from_secs(10))));
+        case!(zero: assert!(parse_duration("0ms").is_err()));
+        case!(negative: assert!(parse_duration("-1s").is_err()));
+        case!(unit: assert!(parse_duration("5d").is_err()));
+    }
+
     #[test]
     fn verify_cli() {
         use clap::CommandFactory;
@@ -0,0 +1,159 @@
+use crate::endpoint::Peer;
+use clap::ValueEnum;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V2_LOCAL: u8 = 0x20;
+const V2_PROXY: u8 = 0x21;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+const V2_UNSPEC: u8 = 0x00;
+
+/// Version of the HAProxy PROXY protocol header to send
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub enum Version {
+    /// Human-readable text header
+    V1,
+    /// Binary header
+    V2,
+}
+
+/// Encodes a header describing a TCP connection from `source` to `destination`.
+///
+/// If only one of the addresses is IPv6, the other is sent as an IPv4-mapped IPv6 address,
+/// since the protocol requires both to be in the same family.
+///
+/// Connections from unix sockets have no addresses to send, so the header tells the destination to use its own.
+pub fn encode(version: Version, source: Peer, destination: Peer) -> Vec<u8> {
+    let (source, destination) = match (source, destination) {
+        (Peer::Inet(source), Peer::Inet(destination)) => (source, destination),
+        (Peer::Unix, _) | (_, Peer::Unix) => return encode_unknown(version),
+    };
+
+    let (source_ip, destination_ip) = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => (IpAddr::V4(s), IpAddr::V4(d)),
+        (s, d) => (IpAddr::V6(to_ipv6(s)), IpAddr::V6(to_ipv6(d))),
+    };
+
+    match version {
+        Version::V1 => {
+            let family = match source_ip {
+                IpAddr::V4(_) => "TCP4",
+                IpAddr::V6(_) => "TCP6",
+            };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family,
+                source_ip,
+                destination_ip,
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        Version::V2 => {
+            let mut buf = Vec::with_capacity(16 + 36);
+            buf.extend_from_slice(&V2_SIGNATURE);
+            buf.push(V2_PROXY);
+            match (source_ip, destination_ip) {
+                (IpAddr::V4(s), IpAddr::V4(d)) => {
+                    buf.push(V2_TCP4);
+                    buf.extend_from_slice(&12u16.to_be_bytes());
+                    buf.extend_from_slice(&s.octets());
+                    buf.extend_from_slice(&d.octets());
+                }
+                (s, d) => {
+                    buf.push(V2_TCP6);
+                    buf.extend_from_slice(&36u16.to_be_bytes());
+                    buf.extend_from_slice(&to_ipv6(s).octets());
+                    buf.extend_from_slice(&to_ipv6(d).octets());
+                }
+            }
+            buf.extend_from_slice(&source.port().to_be_bytes());
+            buf.extend_from_slice(&destination.port().to_be_bytes());
+            buf
+        }
+    }
+}
+
+/// Encodes a header for a connection made by the sender itself (e.g. a health check),
+/// which tells the destination to use the connection's own addresses.
+pub fn encode_local(version: Version) -> Vec<u8> {
+    match version {
+        Version::V1 => b"PROXY UNKNOWN\r\n".to_vec(),
+        Version::V2 => {
+            let mut buf = Vec::with_capacity(16);
+            buf.extend_from_slice(&V2_SIGNATURE);
+            buf.push(V2_LOCAL);
+            buf.push(V2_UNSPEC);
+            buf.extend_from_slice(&0u16.to_be_bytes());
+            buf
+        }
+    }
+}
+
+/// Encodes a header for a connection with no addresses to send.
+fn encode_unknown(version: Version) -> Vec<u8> {
+    match version {
+        Version::V1 => b"PROXY UNKNOWN\r\n".to_vec(),
+        Version::V2 => {
+            let mut buf = Vec::with_capacity(16);
+            buf.extend_from_slice(&V2_SIGNATURE);
+            buf.push(V2_PROXY);
+            buf.push(V2_UNSPEC);
+            buf.extend_from_slice(&0u16.to_be_bytes());
+            buf
+        }
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+pub async fn write_to(
+    writer: impl AsyncWrite + Unpin,
+    version: Version,
+    source: Peer,
+    destination: Peer,
+) -> Result<(), io::Error> {
+    write_header(writer, &encode(version, source, destination)).await
+}
+
+pub async fn write_local_to(
+    writer: impl AsyncWrite + Unpin,
+    version: Version,
+) -> Result<(), io::Error> {
+    write_header(writer, &encode_local(version)).await
+}
+
+async fn write_header(mut writer: impl AsyncWrite + Unpin, header: &[u8]) -> Result<(), io::Error> {
+    writer.write_all(header).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> Peer {
+        Peer::Inet(s.parse().unwrap())
+    }
+
+    case!(v1_ipv4: assert_eq!(encode(Version::V1, addr("1.2.3.4:5678"), addr("10.0.0.1:80")), b"PROXY TCP4 1.2.3.4 10.0.0.1 5678 80\r\n"));
+    case!(v1_ipv6: assert_eq!(encode(Version::V1, addr("[2001:db8::1]:5678"), addr("[::1]:80")), b"PROXY TCP6 2001:db8::1 ::1 5678 80\r\n"));
+    case!(v1_mixed: assert_eq!(encode(Version::V1, addr("1.2.3.4:5678"), addr("[::1]:80")), b"PROXY TCP6 ::ffff:1.2.3.4 ::1 5678 80\r\n"));
+    case!(v2_ipv4: assert_eq!(encode(Version::V2, addr("1.2.3.4:5678"), addr("10.0.0.1:80")), b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c\x01\x02\x03\x04\x0a\x00\x00\x01\x16\x2e\x00\x50"));
+    case!(v1_unix: assert_eq!(encode(Version::V1, Peer::Unix, Peer::Unix), b"PROXY UNKNOWN\r\n"));
+    case!(v2_unix: assert_eq!(encode(Version::V2, Peer::Unix, addr("10.0.0.1:80")), b"\r\n\r\n\0\r\nQUIT\n\x21\x00\x00\x00"));
+    case!(v1_local: assert_eq!(encode_local(Version::V1), b"PROXY UNKNOWN\r\n"));
+    case!(v2_local: assert_eq!(encode_local(Version::V2), b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00"));
+    case!(v2_ipv6_len: assert_eq!(encode(Version::V2, addr("[::1]:5678"), addr("[::1]:80")).len(), 16 + 36));
+}
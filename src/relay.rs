@@ -0,0 +1,274 @@
+use crate::capture::{Direction, Tap};
+use crate::config::COPY_BUFFER_SIZE;
+use crate::opt::parse_duration;
+use clap::Args;
+use std::fmt;
+use std::future::pending;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, copy_bidirectional_with_sizes};
+use tokio::time::{Instant, sleep, sleep_until};
+
+#[derive(Args, Debug)]
+pub struct TimeoutOptions {
+    /// Close relayed connections after this long without data in either direction (e.g. `5m`)
+    #[arg(long, value_parser = parse_duration)]
+    pub idle_timeout: Option<Duration>,
+
+    /// Close relayed connections after this long, even if they're active (e.g. `12h`)
+    #[arg(long, value_parser = parse_duration)]
+    pub max_lifetime: Option<Duration>,
+}
+
+/// When to close relayed connections, regardless of what the peers do.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Timeouts {
+    pub idle: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+}
+
+impl From<TimeoutOptions> for Timeouts {
+    fn from(options: TimeoutOptions) -> Self {
+        let TimeoutOptions {
+            idle_timeout,
+            max_lifetime,
+        } = options;
+        Timeouts {
+            idle: idle_timeout,
+            max_lifetime,
+        }
+    }
+}
+
+/// How a relay ended, and how much it copied each way.
+pub struct Closed {
+    pub a_to_b: u64,
+    pub b_to_a: u64,
+    pub reason: Reason,
+}
+
+pub enum Reason {
+    Finished,
+    Idle(Duration),
+    Lifetime(Duration),
+    Error(io::Error),
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Finished => write!(f, "finished"),
+            Reason::Idle(timeout) => write!(f, "idle for {:?}", timeout),
+            Reason::Lifetime(timeout) => write!(f, "reached max lifetime of {:?}", timeout),
+            Reason::Error(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<Result<(u64, u64), io::Error>> for Closed {
+    fn from(result: Result<(u64, u64), io::Error>) -> Self {
+        match result {
+            Ok((a_to_b, b_to_a)) => Closed {
+                a_to_b,
+                b_to_a,
+                reason: Reason::Finished,
+            },
+            Err(e) => Closed {
+                a_to_b: 0,
+                b_to_a: 0,
+                reason: Reason::Error(e),
+            },
+        }
+    }
+}
+
+/// Copies between `a` and `b` until both sides close, an error occurs, or a timeout expires.
+///
+/// `a` is the client side, for the purposes of capturing.
+pub async fn copy<A, B>(a: &mut A, b: &mut B, timeouts: Timeouts, tap: Option<Tap>) -> Closed
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let start = Instant::now();
+    let activity = Activity {
+        start,
+        last: AtomicU64::new(0),
+    };
+    let mut a = Counted::new(a, &activity, tap.as_ref(), Direction::ToClient);
+    let mut b = Counted::new(b, &activity, tap.as_ref(), Direction::ToServer);
+
+    let reason = tokio::select! {
+        result = copy_bidirectional_with_sizes(&mut a, &mut b, COPY_BUFFER_SIZE, COPY_BUFFER_SIZE) => {
+            match result {
+                Ok(_) => Reason::Finished,
+                Err(e) => Reason::Error(e),
+            }
+        }
+        timeout = idle(&activity, timeouts.idle) => Reason::Idle(timeout),
+        timeout = expire(start, timeouts.max_lifetime) => Reason::Lifetime(timeout),
+    };
+
+    Closed {
+        // bytes written to b came from a, and vice versa
+        a_to_b: b.written,
+        b_to_a: a.written,
+        reason,
+    }
+}
+
+async fn idle(activity: &Activity, timeout: Option<Duration>) -> Duration {
+    let Some(timeout) = timeout else {
+        return pending().await;
+    };
+    loop {
+        let deadline = activity.last() + timeout;
+        if Instant::now() >= deadline {
+            return timeout;
+        }
+        sleep_until(deadline).await;
+    }
+}
+
+async fn expire(start: Instant, timeout: Option<Duration>) -> Duration {
+    match timeout {
+        Some(timeout) => {
+            sleep(timeout.saturating_sub(start.elapsed())).await;
+            timeout
+        }
+        None => pending().await,
+    }
+}
+
+/// When data last moved through a relay, in either direction.
+struct Activity {
+    start: Instant,
+    /// Milliseconds since `start`
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn touch(&self) {
+        self.last
+            .store(self.start.elapsed().as_millis() as u64, Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Relaxed))
+    }
+}
+
+/// Records activity on a stream, and counts (and optionally captures) the bytes written to it.
+struct Counted<'a, S: ?Sized> {
+    inner: &'a mut S,
+    activity: &'a Activity,
+    tap: Option<&'a Tap>,
+    /// Which way data written to this stream is going
+    direction: Direction,
+    written: u64,
+}
+
+impl<'a, S: ?Sized> Counted<'a, S> {
+    fn new(
+        inner: &'a mut S,
+        activity: &'a Activity,
+        tap: Option<&'a Tap>,
+        direction: Direction,
+    ) -> Self {
+        Self {
+            inner,
+            activity,
+            tap,
+            direction,
+            written: 0,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin + ?Sized> AsyncRead for Counted<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut *this.inner).poll_read(cx, buf))?;
+        if buf.filled().len() > before {
+            this.activity.touch();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin + ?Sized> AsyncWrite for Counted<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut *this.inner).poll_write(cx, buf))?;
+        this.written += written as u64;
+        this.activity.touch();
+        if let Some(tap) = this.tap {
+            tap.record(this.direction, &buf[..written]);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+    #[tokio::test]
+    async fn closes_when_idle() {
+        let (mut a, mut a_peer) = duplex(1024);
+        let (mut b, _b_peer) = duplex(1024);
+        a_peer.write_all(b"hello").await.unwrap();
+        let timeouts = Timeouts {
+            idle: Some(Duration::from_millis(50)),
+            max_lifetime: None,
+        };
+        let closed = copy(&mut a, &mut b, timeouts, None).await;
+        assert!(matches!(closed.reason, Reason::Idle(_)));
+        assert_eq!((closed.a_to_b, closed.b_to_a), (5, 0));
+    }
+
+    #[tokio::test]
+    async fn closes_after_lifetime() {
+        let (mut a, mut a_peer) = duplex(1024);
+        let (mut b, mut b_peer) = duplex(1024);
+        let timeouts = Timeouts {
+            idle: Some(Duration::from_millis(50)),
+            max_lifetime: Some(Duration::from_millis(200)),
+        };
+        let chatter = async {
+            let mut buf = [0; 1];
+            loop {
+                sleep(Duration::from_millis(10)).await;
+                a_peer.write_all(b"x").await.unwrap();
+                b_peer.read_exact(&mut buf).await.unwrap();
+            }
+        };
+        let closed = tokio::select! {
+            closed = copy(&mut a, &mut b, timeouts, None) => closed,
+            () = chatter => unreachable!(),
+        };
+        assert!(matches!(closed.reason, Reason::Lifetime(_)));
+        assert!(closed.a_to_b > 5);
+    }
+}
@@ -0,0 +1,93 @@
+use std::io;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::timeout;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+
+/// Cancelled once the process has been asked to stop, so listeners stop accepting new connections.
+static SHUTDOWN: LazyLock<CancellationToken> = LazyLock::new(CancellationToken::new);
+
+/// Connections which should be allowed to finish before the process exits.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static DRAINED: Notify = Notify::const_new();
+
+pub fn requested() -> WaitForCancellationFuture<'static> {
+    SHUTDOWN.cancelled()
+}
+
+pub fn is_requested() -> bool {
+    SHUTDOWN.is_cancelled()
+}
+
+/// Waits for SIGINT or SIGTERM (or Ctrl-C on Windows), and requests shutdown.
+pub async fn wait_for_signal() -> Result<(), io::Error> {
+    let name = signal().await?;
+    log::info!("Received {}, shutting down", name);
+    SHUTDOWN.cancel();
+    Ok(())
+}
+
+/// Waits for active connections to finish, up to `limit`, or until another signal is received.
+pub async fn drain(limit: Duration) {
+    let drained = async {
+        loop {
+            // registered before checking, so a concurrent notification isn't missed
+            let drained = DRAINED.notified();
+            match ACTIVE.load(SeqCst) {
+                0 => return,
+                active => log::info!("Draining {} active connections", active),
+            }
+            drained.await;
+        }
+    };
+
+    tokio::select! {
+        result = timeout(limit, drained) => match result {
+            Ok(()) => log::info!("All connections drained"),
+            Err(_) => log::warn!(
+                "Closing {} connections which didn't finish draining in time",
+                ACTIVE.load(SeqCst)
+            ),
+        },
+        result = signal() => match result {
+            Ok(name) => log::warn!("Received {} again, exiting without draining", name),
+            Err(e) => log::warn!("Failed to wait for another signal: {}", e),
+        },
+    }
+}
+
+/// Marks a connection as active, so shutdown waits for it until the guard is dropped.
+pub fn guard() -> Guard {
+    ACTIVE.fetch_add(1, SeqCst);
+    Guard(())
+}
+
+pub struct Guard(());
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if ACTIVE.fetch_sub(1, SeqCst) == 1 {
+            DRAINED.notify_waiters();
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn signal() -> Result<&'static str, io::Error> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = interrupt.recv() => Ok("SIGINT"),
+        _ = terminate.recv() => Ok("SIGTERM"),
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() -> Result<&'static str, io::Error> {
+    tokio::signal::ctrl_c().await?;
+    Ok("Ctrl-C")
+}
@@ -1,18 +1,23 @@
 use crate::err::{AppliesTo, IoErrorExt};
+use crate::filter::Filter;
 use crate::future::first_ok;
 use std::io;
 use std::net::SocketAddr;
 use tokio::net::{TcpListener, TcpStream};
 
 pub async fn connect(addrs: &[SocketAddr]) -> Result<TcpStream, io::Error> {
-    let stream = first_ok(addrs.iter().map(TcpStream::connect)).await?;
+    let stream = first_ok(addrs.iter().copied().map(TcpStream::connect)).await?;
     stream.set_nodelay(true)?;
     Ok(stream)
 }
 
-pub async fn accept(listener: &mut TcpListener) -> Result<TcpStream, io::Error> {
+/// Accepts the next connection from a peer which the filter permits, closing any others.
+pub async fn accept(listener: &mut TcpListener, filter: &Filter) -> Result<TcpStream, io::Error> {
     loop {
         match listener.accept().await {
+            Ok((_stream, addr)) if !filter.permits(addr.ip()) => {
+                log::debug!("Rejected connection from {}", addr);
+            }
             Ok((stream, _addr)) => {
                 stream.set_nodelay(true)?;
                 return Ok(stream);
@@ -0,0 +1,260 @@
+use clap::Args;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep, sleep};
+
+/// Bytes per second, optionally with a binary suffix (e.g. `512K`, `1.5M`, or `1G`).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Rate(f64);
+
+#[derive(Debug, Error, PartialEq)]
+pub enum BadRate {
+    #[error("rate must be a number of bytes per second, optionally with K, M, or G (e.g. `512K`)")]
+    Invalid,
+    #[error("rate must be greater than zero")]
+    Zero,
+}
+
+impl FromStr for Rate {
+    type Err = BadRate;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (number, scale) = match s.char_indices().last() {
+            Some((i, 'k' | 'K')) => (&s[..i], 1024.0),
+            Some((i, 'm' | 'M')) => (&s[..i], 1024.0 * 1024.0),
+            Some((i, 'g' | 'G')) => (&s[..i], 1024.0 * 1024.0 * 1024.0),
+            _ => (s, 1.0),
+        };
+        let number = number.parse::<f64>().map_err(|_| BadRate::Invalid)?;
+        match number * scale {
+            rate if !rate.is_finite() || rate < 0.0 => Err(BadRate::Invalid),
+            rate if rate < 1.0 => Err(BadRate::Zero),
+            rate => Ok(Rate(rate)),
+        }
+    }
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ThrottleOptions {
+    /// Most bytes per second each connection may send towards the private address (e.g. `512K` or `10M`)
+    #[arg(long)]
+    pub limit_up: Option<Rate>,
+
+    /// Most bytes per second each connection may send back from the private address
+    #[arg(long)]
+    pub limit_down: Option<Rate>,
+
+    /// Most bytes per second all connections to each listener may send towards private addresses, combined
+    #[arg(long)]
+    pub limit_total_up: Option<Rate>,
+
+    /// Most bytes per second all connections to each listener may send back from private addresses, combined
+    #[arg(long)]
+    pub limit_total_down: Option<Rate>,
+}
+
+/// Rate limits for the relays of one listener, which share its total limits.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    up: Option<Rate>,
+    down: Option<Rate>,
+    total_up: Option<Arc<Bucket>>,
+    total_down: Option<Arc<Bucket>>,
+}
+
+impl Limits {
+    /// Creates limits for a new listener, with its own total limits.
+    pub fn new(options: &ThrottleOptions) -> Self {
+        Self {
+            up: options.limit_up,
+            down: options.limit_down,
+            total_up: options
+                .limit_total_up
+                .map(|rate| Arc::new(Bucket::new(rate))),
+            total_down: options
+                .limit_total_down
+                .map(|rate| Arc::new(Bucket::new(rate))),
+        }
+    }
+
+    /// Throttles reads from both ends of a relay, since that's where data enters it.
+    pub fn apply<P, Q>(&self, public: P, private: Q) -> (Throttled<P>, Throttled<Q>) {
+        let buckets = |each: Option<Rate>, total: &Option<Arc<Bucket>>| {
+            each.map(|rate| Arc::new(Bucket::new(rate)))
+                .into_iter()
+                .chain(total.clone())
+                .collect()
+        };
+        (
+            Throttled::new(public, buckets(self.up, &self.total_up)),
+            Throttled::new(private, buckets(self.down, &self.total_down)),
+        )
+    }
+}
+
+/// Token bucket, which holds up to one second's worth of bytes.
+#[derive(Debug)]
+pub struct Bucket {
+    rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(Rate(rate): Rate) -> Self {
+        Self {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Most bytes to wait for at once, so reads are spread smoothly over each second.
+    fn chunk(&self) -> usize {
+        (self.rate / 10.0) as usize
+    }
+
+    /// How long until `len` bytes are available.
+    fn wait_for(&self, len: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        *tokens = (*tokens + (now - *last).as_secs_f64() * self.rate).min(self.rate);
+        *last = now;
+        let missing = len as f64 - *tokens;
+        if missing <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / self.rate)
+        }
+    }
+
+    /// Other relays may have taken from a shared bucket since `wait_for`, so this can go into debt.
+    fn take(&self, len: usize) {
+        self.state.lock().unwrap().0 -= len as f64;
+    }
+}
+
+/// Limits how fast data can be read from a stream, while writes pass through.
+pub struct Throttled<S> {
+    inner: S,
+    buckets: Vec<Arc<Bucket>>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    fn new(inner: S, buckets: Vec<Arc<Bucket>>) -> Self {
+        Self {
+            inner,
+            buckets,
+            sleep: None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.buckets.is_empty() || buf.remaining() == 0 {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+
+        let len = this
+            .buckets
+            .iter()
+            .map(|bucket| bucket.chunk())
+            .fold(buf.remaining(), usize::min)
+            .max(1);
+        loop {
+            if let Some(sleep) = &mut this.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+            let wait = this
+                .buckets
+                .iter()
+                .map(|bucket| bucket.wait_for(len))
+                .max()
+                .unwrap_or_default();
+            if wait.is_zero() {
+                break;
+            }
+            this.sleep = Some(Box::pin(sleep(wait)));
+        }
+
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(len));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        buf.advance(read);
+        for bucket in &this.buckets {
+            bucket.take(read);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rustfmt::skip]
+    mod rate {
+        use super::*;
+
+        case!(bytes: assert_eq!("1000".parse(), Ok(Rate(1000.0))));
+        case!(kibibytes: assert_eq!("512K".parse(), Ok(Rate(512.0 * 1024.0))));
+        case!(fractional: assert_eq!("1.5m".parse(), Ok(Rate(1.5 * 1024.0 * 1024.0))));
+        case!(zero: assert_eq!("0".parse::<Rate>(), Err(BadRate::Zero)));
+        case!(negative: assert_eq!("-1K".parse::<Rate>(), Err(BadRate::Invalid)));
+        case!(unit: assert_eq!("10Mbps".parse::<Rate>(), Err(BadRate::Invalid)));
+    }
+
+    #[test]
+    fn bucket_refills() {
+        let bucket = Bucket::new(Rate(1000.0));
+        assert_eq!(bucket.wait_for(1000), Duration::ZERO);
+        bucket.take(1000);
+        let wait = bucket.wait_for(500);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn passes_data_through() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+        let (mut a, b) = duplex(1024);
+        let mut b = Throttled::new(b, vec![Arc::new(Bucket::new(Rate(1e9)))]);
+        a.write_all(b"hello").await.unwrap();
+        drop(a);
+        let mut read = Vec::new();
+        b.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, b"hello");
+    }
+}
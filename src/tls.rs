@@ -0,0 +1,226 @@
+use crate::config::TLS_HANDSHAKE_TIMEOUT;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, WebPkiSupportedAlgorithms};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::fmt::{self, Debug};
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::timeout;
+use tokio_rustls::{TlsAcceptor, TlsConnector, client, server};
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+pub fn server_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>, io::Error> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    Ok(Arc::new(config))
+}
+
+/// Builds a client config which verifies the server against either:
+/// - a pinned certificate fingerprint, if provided
+/// - the provided CA certificates, if provided
+/// - the system's native roots, otherwise
+pub fn client_config(
+    ca_path: Option<&Path>,
+    fingerprint: Option<Fingerprint>,
+) -> Result<Arc<ClientConfig>, io::Error> {
+    let provider = provider();
+    let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+
+    let config = match fingerprint {
+        Some(fingerprint) => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier {
+                fingerprint,
+                algorithms: provider.signature_verification_algorithms,
+            }))
+            .with_no_client_auth(),
+        None => {
+            let mut roots = RootCertStore::empty();
+            match ca_path {
+                Some(ca_path) => {
+                    for cert in CertificateDer::pem_file_iter(ca_path)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+                    {
+                        let cert =
+                            cert.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                        roots
+                            .add(cert)
+                            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                    }
+                }
+                None => {
+                    let native = rustls_native_certs::load_native_certs();
+                    for e in native.errors {
+                        log::warn!("Failed to load native certificate: {}", e);
+                    }
+                    roots.add_parsable_certificates(native.certs);
+                }
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+    };
+
+    Ok(Arc::new(config))
+}
+
+/// Extracts the hostname to verify from a `host:port` string.
+pub fn server_name(authority: &str) -> Result<ServerName<'static>, io::Error> {
+    let host = match authority.rsplit_once(':') {
+        Some((host, _port)) => host,
+        None => authority,
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Starts TLS over an existing connection, which may be tunneled through a proxy.
+pub async fn connect<S>(
+    stream: S,
+    server_name: &ServerName<'static>,
+    config: &Arc<ClientConfig>,
+) -> Result<client::TlsStream<S>, io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let connector = TlsConnector::from(Arc::clone(config));
+    timeout(
+        TLS_HANDSHAKE_TIMEOUT,
+        connector.connect(server_name.clone(), stream),
+    )
+    .await?
+}
+
+/// Starts TLS on an accepted connection.
+pub async fn accept<S>(stream: S, acceptor: &TlsAcceptor) -> Result<server::TlsStream<S>, io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await?
+}
+
+/// SHA-256 hash of a DER-encoded certificate.
+#[derive(Copy, Clone, PartialEq)]
+pub struct Fingerprint([u8; 32]);
+
+impl Debug for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum BadFingerprint {
+    #[error("invalid hex digit")]
+    InvalidHex,
+    #[error("expected 32 bytes (SHA-256)")]
+    WrongLength,
+}
+
+impl FromStr for Fingerprint {
+    type Err = BadFingerprint;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // allow the colon-separated format that openssl prints
+        let hex = s.bytes().filter(|&b| b != b':').collect::<Vec<_>>();
+        if hex.len() != 64 {
+            return Err(BadFingerprint::WrongLength);
+        }
+        let mut fingerprint = [0; 32];
+        for (byte, pair) in fingerprint.iter_mut().zip(hex.chunks(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| BadFingerprint::InvalidHex)?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| BadFingerprint::InvalidHex)?;
+        }
+        Ok(Fingerprint(fingerprint))
+    }
+}
+
+/// Trusts exactly one certificate, regardless of who issued it or which name it's for.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprint: Fingerprint,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let actual = Sha256::digest(end_entity.as_ref());
+        if actual.as_slice() == self.fingerprint.0 {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+
+    case!(fingerprint_plain: assert!(matches!(Fingerprint::from_str("00112233445566778899aabbccddeeff00112233445566778899AABBCCDDEEFF"), Ok(f) if f.0[1] == 0x11 && f.0[31] == 0xff)));
+    case!(fingerprint_colons: assert!(Fingerprint::from_str("00:11:22:33:44:55:66:77:88:99:aa:bb:cc:dd:ee:ff:00:11:22:33:44:55:66:77:88:99:aa:bb:cc:dd:ee:ff").is_ok()));
+    case!(fingerprint_short: assert!(matches!(Fingerprint::from_str("0011"), Err(BadFingerprint::WrongLength))));
+    case!(fingerprint_bad_hex: assert!(matches!(Fingerprint::from_str("zz112233445566778899aabbccddeeff00112233445566778899aabbccddeeff"), Err(BadFingerprint::InvalidHex))));
+
+    case!(server_name_dns: assert!(matches!(server_name("example.com:443"), Ok(ServerName::DnsName(_)))));
+    case!(server_name_ipv4: assert!(matches!(server_name("127.0.0.1:443"), Ok(ServerName::IpAddress(_)))));
+    case!(server_name_ipv6: assert!(matches!(server_name("[::1]:443"), Ok(ServerName::IpAddress(_)))));
+}
@@ -1,3 +1,4 @@
+use crate::capacity::Capacity;
 use crate::err::Error;
 use crate::http;
 use crate::transmitted::routes::{State, respond_to_request};
@@ -14,6 +15,8 @@ pub async fn main(options: opt::Options) -> Result<(), Error> {
             secret_key,
             no_secret_key,
         },
+        filter,
+        capacity,
     } = options;
 
     let state = State {
@@ -28,7 +31,14 @@ pub async fn main(options: opt::Options) -> Result<(), Error> {
         },
     };
 
-    http::run_simple_server(listen, state, respond_to_request).await?;
+    http::run_simple_server(
+        listen,
+        filter.into(),
+        Capacity::new(&capacity),
+        state,
+        respond_to_request,
+    )
+    .await?;
 
     Ok(())
 }
@@ -1,5 +1,7 @@
+use crate::capacity::CapacityOptions;
+use crate::endpoint::ListenAddr;
+use crate::filter::FilterOptions;
 use clap::Args;
-use std::net::SocketAddr;
 
 #[derive(Args, Debug)]
 #[clap(
@@ -12,11 +14,17 @@ Examples:
 - POST http://localhost:8080/example.com/test.html (defaults to https)"
 )]
 pub struct Options {
-    /// Socket address to listen on
-    pub listen: SocketAddr,
+    /// Socket address to listen on, or a unix socket (e.g. `unix:/run/app.sock`)
+    pub listen: ListenAddr,
 
     #[command(flatten)]
     pub key: KeyOptions,
+
+    #[command(flatten)]
+    pub filter: FilterOptions,
+
+    #[command(flatten)]
+    pub capacity: CapacityOptions,
 }
 
 #[derive(Args, Debug)]
@@ -1,21 +1,43 @@
-use crate::config::COPY_BUFFER_SIZE;
-use crate::tcp;
+use crate::capacity::Capacity;
+use crate::capture::Capture;
+use crate::endpoint::{self, ConnectAddr, ListenAddr};
+use crate::filter::Filter;
+use crate::proxy_protocol;
+use crate::relay::{self, Timeouts};
+use crate::shutdown;
+use crate::throttle::Limits;
 use std::io;
-use std::net::SocketAddr;
 use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
-use tokio::io::copy_bidirectional_with_sizes;
-use tokio::net::TcpListener;
+
+/// How to relay each connection, once it's accepted.
+pub struct Relay {
+    pub proxy_protocol: Option<proxy_protocol::Version>,
+    pub limits: Limits,
+    pub timeouts: Timeouts,
+    pub capture: Option<Capture>,
+}
 
 static ACTIVE: AtomicUsize = AtomicUsize::new(0);
 
-pub async fn run(from_addr: SocketAddr, to_addrs: &[SocketAddr]) -> Result<(), io::Error> {
+pub async fn run(
+    from_addr: &ListenAddr,
+    to_addr: &ConnectAddr,
+    filter: &Filter,
+    capacity: &Capacity,
+    Relay {
+        proxy_protocol,
+        limits,
+        timeouts,
+        capture,
+    }: &Relay,
+) -> Result<(), io::Error> {
     log::info!("Binding to: {}", from_addr);
-    let mut connections = TcpListener::bind(from_addr).await?;
+    let mut connections = endpoint::bind(from_addr).await?;
 
     loop {
-        let mut inbound = tcp::accept(&mut connections).await?;
+        let (inbound, permit) = capacity.accept(&mut connections, filter).await?;
 
-        let mut outbound = match tcp::connect(to_addrs).await {
+        let mut outbound = match endpoint::connect(to_addr).await {
             Ok(outbound) => outbound,
             Err(e) => {
                 log::error!("Failed to connect: {}", e);
@@ -23,20 +45,38 @@ pub async fn run(from_addr: SocketAddr, to_addrs: &[SocketAddr]) -> Result<(), i
             }
         };
 
+        if let Some(version) = *proxy_protocol {
+            let header = async {
+                let source = inbound.peer_addr()?;
+                let destination = inbound.local_addr()?;
+                proxy_protocol::write_to(&mut outbound, version, source, destination).await
+            };
+            if let Err(e) = header.await {
+                log::error!("Failed to send PROXY header: {}", e);
+                continue;
+            }
+        }
+
+        let tap = match (capture, inbound.peer_addr(), outbound.peer_addr()) {
+            (Some(capture), Ok(client), Ok(server)) => capture.start(client, server),
+            _ => None,
+        };
+        let (mut inbound, mut outbound) = limits.apply(inbound, outbound);
+        let timeouts = *timeouts;
+
         log::info!("Spawning ({} active)", ACTIVE.fetch_add(1, Relaxed) + 1);
         tokio::spawn(async move {
-            let done = copy_bidirectional_with_sizes(
-                &mut inbound,
-                &mut outbound,
-                COPY_BUFFER_SIZE,
-                COPY_BUFFER_SIZE,
-            )
-            .await;
+            let _draining = shutdown::guard();
+            let _permit = permit;
+            let closed = relay::copy(&mut inbound, &mut outbound, timeouts, tap).await;
             let active = ACTIVE.fetch_sub(1, Relaxed) - 1;
-            match done {
-                Ok((down, up)) => log::info!("Closing ({} active): {}/{}", active, down, up),
-                Err(e) => log::info!("Closing ({} active): {}", active, e),
-            }
+            log::info!(
+                "Closing ({} active): {}/{}, {}",
+                active,
+                closed.a_to_b,
+                closed.b_to_a,
+                closed.reason
+            );
         });
     }
 }
@@ -1,10 +1,37 @@
+use crate::capacity::Capacity;
+use crate::capture::Capture;
+use crate::throttle::Limits;
+
 mod forwarder;
 pub mod opt;
 
 pub async fn main(options: opt::Options) -> Result<(), std::io::Error> {
-    let opt::Options { listen, to } = options;
+    let opt::Options {
+        listen,
+        to,
+        proxy_protocol,
+        filter,
+        capacity,
+        throttle,
+        timeouts,
+        capture,
+    } = options;
+
+    let relay = forwarder::Relay {
+        proxy_protocol,
+        limits: Limits::new(&throttle),
+        timeouts: timeouts.into(),
+        capture: Capture::new(capture)?,
+    };
 
-    forwarder::run(listen, &to).await?;
+    forwarder::run(
+        &listen,
+        &to,
+        &filter.into(),
+        &Capacity::new(&capacity),
+        &relay,
+    )
+    .await?;
 
     Ok(())
 }
@@ -1,14 +1,40 @@
-use crate::opt::SocketAddrsFromDns;
+use crate::capacity::CapacityOptions;
+use crate::capture::CaptureOptions;
+use crate::endpoint::{ConnectAddr, ListenAddr};
+use crate::filter::FilterOptions;
+use crate::proxy_protocol;
+use crate::relay::TimeoutOptions;
+use crate::throttle::ThrottleOptions;
 use clap::Args;
-use std::net::SocketAddr;
 
 /// Forward TCP connections somewhere else
 #[derive(Args, Debug)]
 pub struct Options {
-    /// Socket address to listen on
-    pub listen: SocketAddr,
+    /// Socket address to listen on, or a unix socket (e.g. `unix:/run/app.sock`)
+    pub listen: ListenAddr,
 
-    /// Address to forward connections to
+    /// Address to forward connections to, or a unix socket (e.g. `unix:/var/run/docker.sock`)
     #[arg(short, long)]
-    pub to: SocketAddrsFromDns,
+    pub to: ConnectAddr,
+
+    /// Send a PROXY protocol header to the destination, carrying the original peer's address.
+    ///
+    /// The destination must be configured to expect this header.
+    #[arg(long, value_enum)]
+    pub proxy_protocol: Option<proxy_protocol::Version>,
+
+    #[command(flatten)]
+    pub filter: FilterOptions,
+
+    #[command(flatten)]
+    pub capacity: CapacityOptions,
+
+    #[command(flatten)]
+    pub throttle: ThrottleOptions,
+
+    #[command(flatten)]
+    pub timeouts: TimeoutOptions,
+
+    #[command(flatten)]
+    pub capture: CaptureOptions,
 }
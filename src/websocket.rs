@@ -1,28 +1,251 @@
+use crate::config::WEBSOCKET_HANDSHAKE_TIMEOUT;
+use crate::tls;
+use bytes::Bytes;
+use futures::{Sink, Stream};
 use http::Uri;
+use rustls::ClientConfig;
+use std::cmp;
 use std::io;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpListener;
-use tokio_tungstenite::{accept_async_with_config, connect_async_with_config};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::{TlsAcceptor, server};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{
+    Connector, WebSocketStream, accept_async_with_config, client_async_tls_with_config,
+};
 
-use crate::tcp;
+/// Starts a WebSocket over an existing connection, which may be tunneled through a proxy.
+pub async fn connect(
+    url: &Uri,
+    stream: TcpStream,
+    tls_config: Option<&Arc<ClientConfig>>,
+) -> Result<impl AsyncRead + AsyncWrite + use<>, io::Error> {
+    let connector = tls_config.map(|config| Connector::Rustls(Arc::clone(config)));
+    let (stream, _) = timeout(
+        WEBSOCKET_HANDSHAKE_TIMEOUT,
+        client_async_tls_with_config(url, stream, None, connector),
+    )
+    .await?
+    .map_err(io::Error::other)?;
+
+    Ok(WsStream::new(stream))
+}
 
-pub async fn connect(url: &Uri) -> Result<impl AsyncRead + AsyncWrite + use<>, io::Error> {
-    let set_nodelay = true;
-    let (stream, _) = connect_async_with_config(url, None, set_nodelay)
-        .await
-        .map_err(io::Error::other)?;
+/// Starts a WebSocket on an accepted connection.
+pub async fn accept<S>(stream: S) -> Result<WsStream<S>, io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let stream = timeout(
+        WEBSOCKET_HANDSHAKE_TIMEOUT,
+        accept_async_with_config(stream, None),
+    )
+    .await?
+    .map_err(io::Error::other)?;
 
-    Ok(stream.into_inner())
+    Ok(WsStream::new(stream))
 }
 
-pub async fn accept(
-    listener: &mut TcpListener,
-) -> Result<impl AsyncRead + AsyncWrite + use<>, io::Error> {
-    let stream = tcp::accept(listener).await?;
+/// Starts TLS, then a WebSocket, on an accepted connection.
+pub async fn accept_tls<S>(
+    stream: S,
+    acceptor: &TlsAcceptor,
+) -> Result<WsStream<server::TlsStream<S>>, io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let stream = tls::accept(stream, acceptor).await?;
+
+    accept(stream).await
+}
+
+/// Byte stream carried in binary WebSocket messages.
+///
+/// Half-closing is signalled with an empty message, since a close frame would end both directions.
+/// A close frame is only sent once both directions are finished (by whichever of shutting down or reading EOF
+/// happens last), and is treated as EOF if received.
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    buffered: Bytes,
+    read_closed: bool,
+    write_closed: bool,
+}
+
+impl<S> WsStream<S> {
+    fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            buffered: Bytes::new(),
+            read_closed: false,
+            write_closed: false,
+        }
+    }
+}
+
+fn to_io_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            io::ErrorKind::BrokenPipe.into()
+        }
+        e => io::Error::other(e),
+    }
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.buffered.is_empty() {
+                let n = cmp::min(buf.remaining(), this.buffered.len());
+                buf.put_slice(&this.buffered.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            if this.read_closed {
+                if this.write_closed {
+                    // both directions are finished, so close the connection properly;
+                    // errors are ignored, since everything the peer sent has already arrived
+                    let _ = ready!(Pin::new(&mut this.inner).poll_close(cx));
+                }
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) if data.is_empty() => this.read_closed = true,
+                Some(Ok(Message::Binary(data))) => this.buffered = data,
+                Some(Ok(Message::Close(_))) | None => this.read_closed = true,
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unexpected text message",
+                    )));
+                }
+                Some(Err(e)) => return Poll::Ready(Err(to_io_error(e))),
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        if this.write_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        // empty messages signal EOF, so never send one for an empty write
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(to_io_error)?;
+        Pin::new(&mut this.inner)
+            .start_send(Message::Binary(Bytes::copy_from_slice(buf)))
+            .map_err(to_io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_flush(cx)
+            .map_err(to_io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.get_mut();
+        if this.read_closed {
+            // both directions are finished, so close the connection properly
+            return Pin::new(&mut this.inner)
+                .poll_close(cx)
+                .map_err(to_io_error);
+        }
+        if !this.write_closed {
+            ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(to_io_error)?;
+            Pin::new(&mut this.inner)
+                .start_send(Message::Binary(Bytes::new()))
+                .map_err(to_io_error)?;
+            this.write_closed = true;
+        }
+        Pin::new(&mut this.inner)
+            .poll_flush(cx)
+            .map_err(to_io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+    use tokio_tungstenite::client_async;
+
+    #[tokio::test]
+    async fn roundtrip_with_half_close() {
+        let (server, client) = duplex(1024);
+        let (server, client) =
+            futures::join!(accept(server), client_async("ws://localhost/ws/", client));
+        let mut server = server.unwrap();
+        let mut client = WsStream::new(client.unwrap().0);
+
+        client.write_all(b"hello").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut buf = Vec::new();
+        server.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hello");
+
+        server.write_all(b"world").await.unwrap();
+        server.shutdown().await.unwrap();
+
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"world");
+    }
+
+    #[tokio::test]
+    async fn closes_after_reading_eof_when_already_shut_down() {
+        use futures::StreamExt;
+
+        let (server, client) = duplex(1024);
+        let (server, client) =
+            futures::join!(accept(server), client_async("ws://localhost/ws/", client));
+        let mut server = server.unwrap();
+        let mut client = WsStream::new(client.unwrap().0);
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
 
-    let stream = accept_async_with_config(stream, None)
-        .await
-        .map_err(io::Error::other)?;
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
 
-    Ok(stream.into_inner())
+        // the client finished both directions when it read EOF, so it must have sent a close frame
+        let mut messages = Vec::new();
+        let received = async {
+            while let Some(Ok(message)) = server.inner.next().await {
+                messages.push(message);
+            }
+        };
+        timeout(std::time::Duration::from_secs(5), received)
+            .await
+            .unwrap();
+        assert!(
+            matches!(messages.as_slice(), [Message::Binary(eof), Message::Close(_)] if eof.is_empty())
+        );
+    }
 }